@@ -0,0 +1,64 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{layer::SubscriberExt as _, Registry};
+use wgpu_subscriber::FmtLayer;
+
+fn file_layer() -> FmtLayer {
+    FmtLayer::with_file(std::env::temp_dir().join("wgpu-subscriber-bench.log")).unwrap()
+}
+
+fn filtered_out(c: &mut Criterion) {
+    let subscriber = Registry::default().with(file_layer().with_max_level(LevelFilter::INFO));
+    tracing::subscriber::with_default(subscriber, || {
+        c.bench_function("filtered out", |b| {
+            b.iter(|| tracing::debug!(value = 42, "filtered out"))
+        });
+    });
+}
+
+fn message_only(c: &mut Criterion) {
+    let subscriber = Registry::default().with(file_layer());
+    tracing::subscriber::with_default(subscriber, || {
+        c.bench_function("message only", |b| b.iter(|| tracing::info!("message")));
+    });
+}
+
+fn with_fields(c: &mut Criterion) {
+    let subscriber = Registry::default().with(file_layer());
+    tracing::subscriber::with_default(subscriber, || {
+        c.bench_function("with fields", |b| {
+            b.iter(|| {
+                tracing::info!(
+                    int = 42,
+                    float = 1.5,
+                    flag = true,
+                    text = "text",
+                    "message {}",
+                    7
+                )
+            })
+        });
+    });
+}
+
+fn nested_spans(c: &mut Criterion) {
+    let subscriber = Registry::default().with(file_layer());
+    tracing::subscriber::with_default(subscriber, || {
+        let outer = tracing::info_span!("outer");
+        let _outer = outer.enter();
+        let middle = tracing::info_span!("middle");
+        let _middle = middle.enter();
+        let inner = tracing::info_span!("inner");
+        let _inner = inner.enter();
+        c.bench_function("nested spans", |b| b.iter(|| tracing::info!("message")));
+    });
+}
+
+criterion_group!(
+    benches,
+    filtered_out,
+    message_only,
+    with_fields,
+    nested_spans
+);
+criterion_main!(benches);
@@ -0,0 +1,365 @@
+//! Formatting core that needs neither `std` nor an allocator.
+//!
+//! [`FmtLayer`](crate::FmtLayer) formats its lines with the functions in here, and
+//! [`BareSubscriber`] uses them to output the same format on targets that only have
+//! `tracing-core`, writing into a user provided [`ByteSink`] and reading time from a
+//! [`TickSource`].
+//!
+//! With the `rtt` feature, [`RttSink`] sends the output over a SEGGER RTT up channel.
+
+use core::{
+    fmt::{self, Write as _},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use tracing::{
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span, Event, Level, Metadata, Subscriber,
+};
+
+#[cfg(feature = "rtt")]
+pub use crate::rtt::RttSink;
+
+/// Destination for formatted output.
+///
+/// Lines are handed over in chunks of at most [`LINE_CHUNK`] bytes, the last chunk
+/// of every line ends with `\n`.
+pub trait ByteSink {
+    fn write_bytes(&self, bytes: &[u8]);
+}
+
+/// Monotonic clock used for timestamps.
+pub trait TickSource {
+    /// Microseconds since some fixed point, usually boot.
+    fn now_micros(&self) -> u64;
+}
+
+/// Size of the buffer lines are assembled in before they are written to a [`ByteSink`].
+pub const LINE_CHUNK: usize = 128;
+
+pub(crate) fn level_str(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "ERROR",
+        Level::WARN => "WARN",
+        Level::INFO => "INFO",
+        Level::DEBUG => "DEBUG",
+        Level::TRACE => "TRACE",
+    }
+}
+
+/// Writes the start of a line, `[secs.micros LEVEL](span | span)(module): `.
+pub(crate) fn write_header<'a>(
+    out: &mut impl fmt::Write,
+    micros: u64,
+    level: &Level,
+    spans: impl Iterator<Item = &'a str>,
+    module: &str,
+) -> fmt::Result {
+    out.write_char('[')?;
+    write_secs(out, micros)?;
+    out.write_char(' ')?;
+    write_scope(out, level_str(level), spans, module)
+}
+
+/// Writes `micros` as seconds, `secs.micros`.
+pub(crate) fn write_secs(out: &mut impl fmt::Write, micros: u64) -> fmt::Result {
+    let mut itoa = itoa::Buffer::new();
+    out.write_str(itoa.format(micros / 1_000_000))?;
+    out.write_char('.')?;
+    let fraction = itoa.format(micros % 1_000_000);
+    out.write_str(&"000000"[fraction.len()..])?;
+    out.write_str(fraction)
+}
+
+/// Writes the end of the header after the timestamp, `LEVEL](span | span)(module): `.
+pub(crate) fn write_scope<'a>(
+    out: &mut impl fmt::Write,
+    level: &str,
+    spans: impl Iterator<Item = &'a str>,
+    module: &str,
+) -> fmt::Result {
+    out.write_str(level)?;
+    out.write_str("](")?;
+    for (i, span) in spans.enumerate() {
+        if i != 0 {
+            out.write_str(" | ")?;
+        }
+        out.write_str(span)?;
+    }
+    out.write_str(")(")?;
+    out.write_str(module)?;
+    out.write_str("): ")
+}
+
+/// Writes a field after the message, ` name=value`.
+pub(crate) fn write_field(out: &mut impl fmt::Write, name: &str, value: impl fmt::Display) {
+    let _ = write!(out, " {}={}", name, value);
+}
+
+/// Assembles a line in a fixed buffer, spilling into the sink when it is full.
+struct LineWriter<'a, S: ByteSink> {
+    sink: &'a S,
+    buffer: [u8; LINE_CHUNK],
+    len: usize,
+}
+
+impl<'a, S: ByteSink> LineWriter<'a, S> {
+    fn new(sink: &'a S) -> Self {
+        LineWriter {
+            sink,
+            buffer: [0; LINE_CHUNK],
+            len: 0,
+        }
+    }
+
+    fn finish(mut self, terminator: u8) {
+        self.write_bytes(&[terminator]);
+        self.sink.write_bytes(&self.buffer[..self.len]);
+    }
+
+    fn write_bytes(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            if self.len == LINE_CHUNK {
+                self.sink.write_bytes(&self.buffer);
+                self.len = 0;
+            }
+            let n = bytes.len().min(LINE_CHUNK - self.len);
+            self.buffer[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+            self.len += n;
+            bytes = &bytes[n..];
+        }
+    }
+}
+
+impl<S: ByteSink> fmt::Write for LineWriter<'_, S> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Writes strings of a [`Encoding::Compact`] record, without the NUL bytes that
+/// terminate them.
+struct CompactStr<'a, 'b, S: ByteSink>(&'a mut LineWriter<'b, S>);
+
+impl<S: ByteSink> fmt::Write for CompactStr<'_, '_, S> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for part in s.split('\0') {
+            self.0.write_bytes(part.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+/// How [`BareSubscriber`] encodes events.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Encoding {
+    /// The lines [`FmtLayer`](crate::FmtLayer) outputs.
+    Text,
+    /// A binary encoding for slow links, decoded by [`decode_compact`]. Every record is a
+    /// level byte (1 for ERROR to 5 for TRACE), the timestamp in microseconds as LEB128,
+    /// and the module and message as UTF-8, each terminated by a NUL byte. NUL bytes
+    /// inside the message are dropped.
+    Compact,
+}
+
+/// A record decoded by [`decode_compact`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct CompactRecord<'a> {
+    pub level: Level,
+    pub micros: u64,
+    pub module: &'a str,
+    pub message: &'a str,
+}
+
+/// Decodes the first [`Encoding::Compact`] record in `bytes`, returning it and the
+/// remaining bytes. Returns `None` if `bytes` doesn't start with a complete, valid record.
+pub fn decode_compact(bytes: &[u8]) -> Option<(CompactRecord<'_>, &[u8])> {
+    let (&level, mut rest) = bytes.split_first()?;
+    let level = match level {
+        1 => Level::ERROR,
+        2 => Level::WARN,
+        3 => Level::INFO,
+        4 => Level::DEBUG,
+        5 => Level::TRACE,
+        _ => return None,
+    };
+
+    let mut micros = 0u64;
+    let mut shift = 0;
+    loop {
+        let (&byte, next) = rest.split_first()?;
+        rest = next;
+        micros |= u64::from(byte & 0x7f).checked_shl(shift)?;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let mut strings = rest.splitn(3, |&byte| byte == 0);
+    let module = core::str::from_utf8(strings.next()?).ok()?;
+    let message = core::str::from_utf8(strings.next()?).ok()?;
+    let rest = strings.next()?;
+
+    let record = CompactRecord {
+        level,
+        micros,
+        module,
+        message,
+    };
+    Some((record, rest))
+}
+
+/// Writes the message of an event, or all its other fields, straight into the line.
+struct MessageVisitor<'a, W: fmt::Write> {
+    out: &'a mut W,
+    message: bool,
+}
+
+impl<W: fmt::Write> Visit for MessageVisitor<'_, W> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match (field.name(), self.message) {
+            ("message", true) => {
+                let _ = self.out.write_str(value);
+            }
+            ("message", false) | (_, true) => {}
+            (name, false) => write_field(self.out, name, value),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match (field.name(), self.message) {
+            ("message", true) => {
+                let _ = write!(self.out, "{:?}", value);
+            }
+            ("message", false) | (_, true) => {}
+            (name, false) => write_field(self.out, name, format_args!("{:?}", value)),
+        }
+    }
+}
+
+/// A [`Subscriber`] that outputs the same lines as [`FmtLayer`](crate::FmtLayer)
+/// without `std`.
+///
+/// There is no span registry, so the span scope of every line is empty.
+///
+/// ```ignore
+/// tracing::dispatcher::set_global_default(tracing::Dispatch::new(
+///     BareSubscriber::new(MySink, MyClock).with_max_level(LevelFilter::INFO),
+/// ));
+/// ```
+pub struct BareSubscriber<S, T> {
+    sink: S,
+    ticks: T,
+    max_level: LevelFilter,
+    encoding: Encoding,
+    next_id: AtomicUsize,
+}
+
+impl<S: ByteSink, T: TickSource> BareSubscriber<S, T> {
+    pub fn new(sink: S, ticks: T) -> Self {
+        BareSubscriber {
+            sink,
+            ticks,
+            max_level: LevelFilter::TRACE,
+            encoding: Encoding::Text,
+            next_id: AtomicUsize::new(1),
+        }
+    }
+
+    /// Only output events at `level` or above.
+    pub fn with_max_level(mut self, level: impl Into<LevelFilter>) -> Self {
+        self.max_level = level.into();
+        self
+    }
+
+    /// Encode events with `encoding`, [`Encoding::Text`] by default.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+}
+
+impl<S, T> Subscriber for BareSubscriber<S, T>
+where
+    S: ByteSink + Send + Sync + 'static,
+    T: TickSource + Send + Sync + 'static,
+{
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        crate::statically_enabled(metadata.level()) && *metadata.level() <= self.max_level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.max_level)
+    }
+
+    fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) as u64)
+    }
+
+    fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let metadata = event.metadata();
+        let micros = self.ticks.now_micros();
+        let module = metadata.module_path().unwrap_or("no module");
+        let mut line = LineWriter::new(&self.sink);
+
+        match self.encoding {
+            Encoding::Text => {
+                let _ = write_header(
+                    &mut line,
+                    micros,
+                    metadata.level(),
+                    core::iter::empty(),
+                    module,
+                );
+                event.record(&mut MessageVisitor {
+                    out: &mut line,
+                    message: true,
+                });
+                event.record(&mut MessageVisitor {
+                    out: &mut line,
+                    message: false,
+                });
+                line.finish(b'\n');
+            }
+            Encoding::Compact => {
+                let level = match *metadata.level() {
+                    Level::ERROR => 1,
+                    Level::WARN => 2,
+                    Level::INFO => 3,
+                    Level::DEBUG => 4,
+                    Level::TRACE => 5,
+                };
+                line.write_bytes(&[level]);
+
+                let mut micros = micros;
+                loop {
+                    let byte = (micros & 0x7f) as u8;
+                    micros >>= 7;
+                    if micros == 0 {
+                        line.write_bytes(&[byte]);
+                        break;
+                    }
+                    line.write_bytes(&[byte | 0x80]);
+                }
+
+                let _ = CompactStr(&mut line).write_str(module);
+                line.write_bytes(&[0]);
+                event.record(&mut MessageVisitor {
+                    out: &mut CompactStr(&mut line),
+                    message: true,
+                });
+                line.finish(0);
+            }
+        }
+    }
+
+    fn enter(&self, _span: &span::Id) {}
+
+    fn exit(&self, _span: &span::Id) {}
+}
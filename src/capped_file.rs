@@ -0,0 +1,215 @@
+use crate::writer::SinkEvent;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Read as _, Seek as _, SeekFrom, Write as _},
+    path::{Path, PathBuf},
+};
+
+/// A file kept under a size limit by dropping its oldest lines.
+///
+/// When a line would grow it past `max_bytes`, only the newest lines filling up to half of
+/// it are kept, so it is rewritten once per half of its size rather than on every line.
+/// Lines longer than that are cut.
+pub(crate) struct CappedFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    len: u64,
+    max_bytes: u64,
+    /// Whether the previous line was cut or truncated the file. The line after is likely
+    /// its announcement, which isn't announced again so small files don't loop.
+    cut: bool,
+    truncated: bool,
+    events: Vec<SinkEvent>,
+}
+
+impl CappedFile {
+    /// Opens the file at `path`, keeping lines left in it by a previous run.
+    pub(crate) fn open(path: &Path, max_bytes: u64) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let len = file.seek(SeekFrom::End(0))?;
+        Ok(CappedFile {
+            path: path.to_owned(),
+            file: BufWriter::new(file),
+            len,
+            max_bytes,
+            cut: false,
+            truncated: false,
+            events: Vec::new(),
+        })
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub(crate) fn write_line(&mut self, line: &str, flush: bool) -> io::Result<()> {
+        // Half of `max_bytes` is left after truncating, a longer line wouldn't fit.
+        let limit = (self.max_bytes / 2) as usize;
+        if limit == 0 {
+            return Ok(());
+        }
+        let mut line = line;
+        let cut = line.len() >= limit;
+        if cut {
+            let mut end = limit - 1;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            if !self.cut {
+                self.events.push(
+                    SinkEvent::new("sink line too long, cutting it", &self.path)
+                        .with_field("bytes", (line.len() as u64).into()),
+                );
+            }
+            line = &line[..end];
+        }
+        self.cut = cut;
+
+        let size = line.len() as u64 + 1;
+        let truncate = self.len + size > self.max_bytes;
+        if truncate {
+            self.truncate(!self.truncated)?;
+        }
+        self.truncated = truncate;
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.len += size;
+        if flush {
+            self.file.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Drops the oldest lines, keeping at most half of `max_bytes`. Only the kept lines are
+    /// read, a chunk at a time.
+    fn truncate(&mut self, announce: bool) -> io::Result<()> {
+        self.file.flush()?;
+        let file = self.file.get_mut();
+        let len = file.seek(SeekFrom::End(0))?;
+        let mut buffer = [0; 8192];
+
+        // Start at the first whole line.
+        let mut start = len.saturating_sub(self.max_bytes / 2);
+        if start != 0 {
+            file.seek(SeekFrom::Start(start - 1))?;
+            start = len;
+            let mut pos = file.stream_position()?;
+            loop {
+                let read = file.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                if let Some(end) = buffer[..read].iter().position(|&byte| byte == b'\n') {
+                    start = pos + end as u64 + 1;
+                    break;
+                }
+                pos += read as u64;
+            }
+        }
+
+        let mut read_pos = start;
+        let mut write_pos = 0;
+        while read_pos < len {
+            file.seek(SeekFrom::Start(read_pos))?;
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            file.seek(SeekFrom::Start(write_pos))?;
+            file.write_all(&buffer[..read])?;
+            read_pos += read as u64;
+            write_pos += read as u64;
+        }
+        file.set_len(write_pos)?;
+        file.seek(SeekFrom::End(0))?;
+        self.len = write_pos;
+
+        if announce {
+            self.events.push(
+                SinkEvent::new("sink truncated, dropping oldest lines", &self.path)
+                    .with_field("bytes", start.into()),
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    pub(crate) fn take_events(&mut self, out: &mut Vec<SinkEvent>) {
+        out.append(&mut self.events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("capped-{}-{}.log", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn keeps_newest_whole_lines() {
+        let path = temp_path("newest");
+        let max_bytes = 40_000;
+        let mut file = CappedFile::open(&path, max_bytes).unwrap();
+        let lines: Vec<_> = (0..5_000).map(|i| format!("line {}", i)).collect();
+        let mut events = Vec::new();
+        for line in &lines {
+            let truncations = events.len();
+            file.write_line(line, false).unwrap();
+            file.take_events(&mut events);
+            if events.len() > truncations {
+                // Only the line written after truncating is past half of the limit.
+                assert!(file.len() <= max_bytes / 2 + line.len() as u64 + 1);
+            }
+        }
+        file.flush().unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(contents.len() as u64, file.len());
+        assert!(file.len() <= max_bytes);
+        let kept: Vec<_> = contents.lines().collect();
+        assert_eq!(kept, lines[lines.len() - kept.len()..]);
+    }
+
+    #[test]
+    fn cuts_long_lines_on_char_boundary() {
+        let path = temp_path("cut");
+        let mut file = CappedFile::open(&path, 20).unwrap();
+        file.write_line("ééééééé", true).unwrap();
+        let mut events = Vec::new();
+        file.take_events(&mut events);
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(contents, "éééé\n");
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn truncates_file_reopened_over_cap() {
+        let path = temp_path("reopen");
+        let previous: String = (0..10).map(|i| format!("line {}\n", i)).collect();
+        fs::write(&path, &previous).unwrap();
+        let mut file = CappedFile::open(&path, 40).unwrap();
+        assert_eq!(file.len(), previous.len() as u64);
+        file.write_line("new", true).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(contents, "line 8\nline 9\nnew\n");
+    }
+}
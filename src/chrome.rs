@@ -1,4 +1,4 @@
-use crate::CURRENT_THREAD_ID;
+use crate::{sampling::is_sampled, CURRENT_THREAD_ID};
 use parking_lot::Mutex;
 use std::{
     borrow::Cow,
@@ -130,9 +130,8 @@ struct EventVisitor {
 
 impl Visit for EventVisitor {
     fn record_bool(&mut self, field: &Field, value: bool) {
-        match field.name() {
-            "trace" => self.trace = value,
-            _ => {}
+        if field.name() == "trace" {
+            self.trace = value;
         }
     }
 
@@ -150,6 +149,10 @@ where
     S: Subscriber + for<'span> LookupSpan<'span>,
 {
     fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        if !crate::statically_enabled(event.metadata().level()) {
+            return;
+        }
+
         let mut event_visitor = EventVisitor::default();
         event.record(&mut event_visitor);
 
@@ -158,6 +161,9 @@ where
 
     fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
         let span = ctx.span(id).unwrap();
+        if !is_sampled(&span) {
+            return;
+        }
         self.write_event(None, span.metadata(), EventType::Begin);
     }
 
@@ -167,6 +173,9 @@ where
         }
 
         let span = ctx.span(id).unwrap();
+        if !is_sampled(&span) {
+            return;
+        }
         self.write_event(None, span.metadata(), EventType::End);
     }
 }
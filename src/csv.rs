@@ -0,0 +1,185 @@
+use crate::{bare, BuildError, LogRecord};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::{borrow::Cow, fmt::Write as _};
+
+/// Columns and delimiter of [`Format::Csv`](crate::Format::Csv).
+///
+/// Values containing the delimiter, quotes or line breaks are quoted, with quotes
+/// doubled. Sinks start with a header row naming the columns, on stdout and stderr each
+/// for [`Sink::stdout`](crate::Sink::stdout), and not in files appended to that already
+/// hold lines.
+///
+/// ```
+/// # use wgpu_subscriber::{Csv, CsvColumn, Format};
+/// let format = Format::Csv(Csv::new().with_columns(vec![
+///     CsvColumn::Timestamp,
+///     CsvColumn::Level,
+///     CsvColumn::Message,
+///     CsvColumn::field("request_id"),
+/// ]));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Csv {
+    columns: Vec<CsvColumn>,
+    delimiter: char,
+}
+
+/// A column of [`Csv`] output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CsvColumn {
+    /// UTC wall clock time in RFC 3339.
+    Timestamp,
+    /// Microseconds since the layer was created.
+    Elapsed,
+    Level,
+    Target,
+    Module,
+    /// Names of the spans, outermost first, separated by `>`.
+    Spans,
+    Message,
+    /// A field of the event or, if it has none of that name, of its innermost span
+    /// having one. Empty if there is none.
+    Field(Cow<'static, str>),
+    TraceId,
+    SpanId,
+}
+
+impl CsvColumn {
+    pub fn field(name: impl Into<Cow<'static, str>>) -> Self {
+        CsvColumn::Field(name.into())
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            CsvColumn::Timestamp => "timestamp",
+            CsvColumn::Elapsed => "elapsed_us",
+            CsvColumn::Level => "level",
+            CsvColumn::Target => "target",
+            CsvColumn::Module => "module",
+            CsvColumn::Spans => "spans",
+            CsvColumn::Message => "message",
+            CsvColumn::Field(name) => name,
+            CsvColumn::TraceId => "trace_id",
+            CsvColumn::SpanId => "span_id",
+        }
+    }
+}
+
+impl Csv {
+    /// Comma separated `timestamp`, `level`, `target` and `message`.
+    pub fn new() -> Self {
+        Csv {
+            columns: vec![
+                CsvColumn::Timestamp,
+                CsvColumn::Level,
+                CsvColumn::Target,
+                CsvColumn::Message,
+            ],
+            delimiter: ',',
+        }
+    }
+
+    /// Like [`Csv::new`], but separated by tabs.
+    pub fn tsv() -> Self {
+        Self::new().with_delimiter('\t')
+    }
+
+    pub fn with_columns(mut self, columns: Vec<CsvColumn>) -> Self {
+        self.columns = columns;
+        self
+    }
+
+    pub fn with_delimiter(mut self, delimiter: char) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Fails if the delimiter can't be told apart from quoted values and line breaks.
+    pub(crate) fn check(&self) -> Result<(), BuildError> {
+        match self.delimiter {
+            '"' | '\n' | '\r' => Err(BuildError::CsvDelimiter(self.delimiter)),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn write_header(&self, out: &mut String) {
+        for (i, column) in self.columns.iter().enumerate() {
+            if i != 0 {
+                out.push(self.delimiter);
+            }
+            self.write_value(out, column.name());
+        }
+    }
+
+    pub(crate) fn write(&self, record: &LogRecord, out: &mut String) {
+        let mut value = String::new();
+        for (i, column) in self.columns.iter().enumerate() {
+            if i != 0 {
+                out.push(self.delimiter);
+            }
+            value.clear();
+            match column {
+                CsvColumn::Timestamp => {
+                    let timestamp = DateTime::<Utc>::from(record.timestamp);
+                    value.push_str(&timestamp.to_rfc3339_opts(SecondsFormat::Micros, true));
+                }
+                CsvColumn::Elapsed => {
+                    let _ = write!(value, "{}", record.time.as_micros());
+                }
+                CsvColumn::Level => value.push_str(bare::level_str(&record.level)),
+                CsvColumn::Target => value.push_str(&record.target),
+                CsvColumn::Module => value.push_str(record.module.as_deref().unwrap_or_default()),
+                CsvColumn::Spans => {
+                    for (i, span) in record.spans.iter().enumerate() {
+                        if i != 0 {
+                            value.push('>');
+                        }
+                        value.push_str(&span.name);
+                    }
+                }
+                CsvColumn::Message => value.push_str(&record.message),
+                CsvColumn::Field(name) => {
+                    if let Some((_, field)) = record.all_fields().find(|(field, _)| field == name) {
+                        let _ = write!(value, "{}", field);
+                    }
+                }
+                CsvColumn::TraceId => {
+                    if let Some(trace_id) = record.trace_id {
+                        let _ = write!(value, "{:032x}", trace_id);
+                    }
+                }
+                CsvColumn::SpanId => {
+                    if let Some(span_id) = record.span_id {
+                        let _ = write!(value, "{:016x}", span_id);
+                    }
+                }
+            }
+            self.write_value(out, &value);
+        }
+    }
+
+    fn write_value(&self, out: &mut String, value: &str) {
+        let quote = value
+            .chars()
+            .any(|c| c == self.delimiter || c == '"' || c == '\n' || c == '\r');
+        if !quote {
+            out.push_str(value);
+            return;
+        }
+
+        out.push('"');
+        for c in value.chars() {
+            if c == '"' {
+                out.push('"');
+            }
+            out.push(c);
+        }
+        out.push('"');
+    }
+}
+
+impl Default for Csv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
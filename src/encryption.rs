@@ -0,0 +1,84 @@
+//! At-rest encryption for file output.
+//!
+//! Every line is sealed on its own with a libsodium style sealed box for the
+//! recipient's public key, so only the holder of the matching secret key can
+//! read the log back. A file is a sequence of frames, each a little endian `u32`
+//! length followed by that many bytes of sealed box.
+
+use crypto_box::aead::OsRng;
+pub use crypto_box::{PublicKey, SecretKey};
+use std::{
+    fs::File,
+    io::{self, Read, Write as _},
+    path::Path,
+};
+
+pub(crate) struct EncryptedFile {
+    file: io::BufWriter<File>,
+    recipient: PublicKey,
+}
+
+impl EncryptedFile {
+    pub(crate) fn create(path: &Path, recipient: PublicKey) -> io::Result<Self> {
+        File::create(path).map(|file| EncryptedFile {
+            file: io::BufWriter::new(file),
+            recipient,
+        })
+    }
+
+    pub(crate) fn write_record(&mut self, record: &[u8]) -> io::Result<()> {
+        let sealed = self
+            .recipient
+            .seal(&mut OsRng, record)
+            .map_err(|_| io::Error::other("failed to seal log record"))?;
+
+        let mut frame = Vec::with_capacity(4 + sealed.len());
+        frame.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&sealed);
+        // One write per frame, so a crash can at worst lose the tail of the file.
+        self.file.write_all(&frame)
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Decrypt a log written with [`FmtLayer::with_encrypted_file`](crate::FmtLayer::with_encrypted_file).
+///
+/// Returns the lines in the order they were written. A truncated final frame is
+/// ignored, anything that fails to decrypt is an error.
+pub fn decrypt_log(mut reader: impl Read, key: &SecretKey) -> io::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    loop {
+        let mut len = [0; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+
+        // Read up to the length instead of allocating it up front, it isn't authenticated.
+        let len = u32::from_le_bytes(len);
+        let mut sealed = Vec::new();
+        (&mut reader)
+            .take(u64::from(len))
+            .read_to_end(&mut sealed)?;
+        if sealed.len() != len as usize {
+            break;
+        }
+
+        let plain = key.unseal(&sealed).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "failed to decrypt log record")
+        })?;
+        let line = String::from_utf8(plain)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        lines.push(line);
+    }
+    Ok(lines)
+}
+
+/// Decrypt the log file at `path`. See [`decrypt_log`].
+pub fn decrypt_log_file(path: impl AsRef<Path>, key: &SecretKey) -> io::Result<Vec<String>> {
+    decrypt_log(io::BufReader::new(File::open(path)?), key)
+}
@@ -0,0 +1,59 @@
+//! Flushing buffered output from a task on a tokio runtime.
+
+use crate::writer::Sink;
+use parking_lot::Mutex;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::oneshot,
+    task::{self, JoinHandle},
+};
+
+/// Sinks flushed by a [`FlushTask`], sinks added to the layer later join them.
+pub(crate) type FlushedSinks = Arc<Mutex<Vec<Sink>>>;
+
+/// A task flushing the sinks of a [`FmtLayer`](crate::FmtLayer) on an interval, see
+/// [`FmtLayer::with_flush_task`](crate::FmtLayer::with_flush_task).
+///
+/// Dropping it stops the task after a final flush, [`FlushTask::shutdown`] also waits
+/// for it. Events arriving after that are still output, but flushed one line at a time. If
+/// the runtime stops first, lines buffered since the last flush are only written when a
+/// [`FmtGuard`](crate::FmtGuard) is dropped, or the buffer fills up.
+#[must_use = "the task stops when this is dropped"]
+pub struct FlushTask {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl FlushTask {
+    /// Spawns the task on the current runtime.
+    pub(crate) fn spawn(sinks: FlushedSinks, interval: Duration) -> Self {
+        let (stop, mut stopped) = oneshot::channel();
+        let handle = tokio::spawn(async move {
+            // Also ends when the sender is dropped.
+            while tokio::time::timeout(interval, &mut stopped).await.is_err() {
+                let sinks = sinks.clone();
+                // Writing to files and sockets blocks, so it is kept off the workers.
+                let _ = task::spawn_blocking(move || {
+                    for sink in sinks.lock().iter() {
+                        let _ = sink.output.flush();
+                    }
+                })
+                .await;
+            }
+            let _ = task::spawn_blocking(move || {
+                for sink in sinks.lock().iter() {
+                    sink.shut_down();
+                }
+            })
+            .await;
+        });
+        FlushTask { stop, handle }
+    }
+
+    /// Stops the task, resolving after the final flush.
+    pub async fn shutdown(self) {
+        let FlushTask { stop, handle } = self;
+        let _ = stop.send(());
+        let _ = handle.await;
+    }
+}
@@ -1,58 +1,1021 @@
-use std::{fmt, io, time::Instant};
+use crate::{
+    format::{DeltaScope, FieldOrder, Format, FormatOptions, Localization, SpanAnchor, Timestamps},
+    guard::{EventCounts, FmtGuard},
+    heartbeat::Heartbeat,
+    mapping::{FieldMapping, LevelMapping},
+    metrics::MetricRules,
+    record::{FieldValue, LogRecord, RecordVisitor, SpanLimits, SpanRecord},
+    sampling::TailSampling,
+    stats::{dropped_events, record_drop, DropReason, DroppedEvents},
+    storm::{StormChange, StormProtection},
+    table::{GroupBy, ProblemTable},
+    util::random_u64,
+    writer::{self, Flush, Sink, SinkEvent},
+};
+use parking_lot::Mutex;
+use smallvec::SmallVec;
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{HashMap, VecDeque},
+    fmt, io,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 use tracing::{
     field::{Field, Visit},
-    Event, Level, Subscriber,
+    level_filters::LevelFilter,
+    span,
+    subscriber::Interest,
+    Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    layer::Context,
+    registry::{LookupSpan, SpanRef},
+    Layer,
 };
-use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
 
+type Hook = Box<dyn Fn(&LogRecord) + Send + Sync>;
+
+thread_local! {
+    /// Set while hooks run, so events logged by a hook don't trigger hooks again.
+    static IN_HOOK: Cell<bool> = const { Cell::new(false) };
+    static LINE_BUFFER: RefCell<String> = RefCell::new(String::with_capacity(256));
+    /// Time of the previous event on this thread per layer, for [`DeltaScope::Thread`].
+    static LAST_EVENT: RefCell<LastEvent> = RefCell::new(LastEvent::default());
+}
+
+static NEXT_LAYER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Micros since `start` of the previous event, per layer id.
 #[derive(Debug, Default)]
-struct FmtEventVisitor {
-    message: String,
+struct LastEvent(SmallVec<[(usize, u64); 2]>);
+
+impl LastEvent {
+    /// Stores `micros` for `layer`, returning the previous value.
+    fn swap(&mut self, layer: usize, micros: u64) -> Option<u64> {
+        match self.0.iter_mut().find(|(id, _)| *id == layer) {
+            Some((_, last)) => Some(std::mem::replace(last, micros)),
+            None => {
+                self.0.push((layer, micros));
+                None
+            }
+        }
+    }
 }
 
-impl Visit for FmtEventVisitor {
+/// Collects the fields of a span, picking out its name field.
+#[derive(Debug)]
+struct SpanFieldVisitor {
+    name_field: &'static str,
+    display_name: Option<String>,
+    /// Set if the span has an `error` field.
+    error: bool,
+    fields: RecordVisitor,
+}
+
+impl SpanFieldVisitor {
+    fn new(name_field: &'static str) -> Self {
+        SpanFieldVisitor {
+            name_field,
+            display_name: None,
+            error: false,
+            fields: RecordVisitor::default(),
+        }
+    }
+
+    /// The collected fields, a `message` field included.
+    fn into_fields(self) -> impl Iterator<Item = (Cow<'static, str>, FieldValue)> {
+        let RecordVisitor { message, fields } = self.fields;
+        let message =
+            (!message.is_empty()).then_some((Cow::Borrowed("message"), FieldValue::Str(message)));
+        message.into_iter().chain(fields)
+    }
+}
+
+impl Visit for SpanFieldVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.error |= field.name() == "error";
+        self.fields.record_i64(field, value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.error |= field.name() == "error";
+        self.fields.record_u64(field, value);
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.error |= field.name() == "error";
+        self.fields.record_f64(field, value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.error |= field.name() == "error";
+        self.fields.record_bool(field, value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == self.name_field {
+            self.display_name = Some(value.to_owned());
+        } else {
+            self.error |= field.name() == "error";
+            self.fields.record_str(field, value);
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.error |= field.name() == "error";
+        self.fields.record_error(field, value);
+    }
+
     fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
-        match field.name() {
-            "message" => self.message = format!("{:?}", value),
-            _ => {}
+        if field.name() == self.name_field {
+            self.display_name = Some(format!("{:?}", value));
+        } else {
+            self.error |= field.name() == "error";
+            self.fields.record_debug(field, value);
         }
     }
 }
 
-enum StandardOutput {
-    Out(io::Stdout),
-    Err(io::Stderr),
+/// The span as each layer outputs it, formatted once when its fields change instead of
+/// for every event inside it.
+///
+/// Untracked spans don't count against [`SpanLimits::max_tracked_spans`] and are never
+/// updated.
+#[derive(Default)]
+struct CachedSpans(SmallVec<[(usize, Arc<SpanRecord>, bool); 1]>);
+
+impl CachedSpans {
+    /// The record of `layer` and whether it is tracked.
+    fn get(&self, layer: usize) -> Option<(&Arc<SpanRecord>, bool)> {
+        self.0
+            .iter()
+            .find(|(id, ..)| *id == layer)
+            .map(|(_, record, tracked)| (record, *tracked))
+    }
+
+    /// The record of `layer` if it is tracked.
+    fn tracked_mut(&mut self, layer: usize) -> Option<&mut Arc<SpanRecord>> {
+        self.0
+            .iter_mut()
+            .find(|(id, _, tracked)| *id == layer && *tracked)
+            .map(|(_, record, _)| record)
+    }
+
+    fn insert(&mut self, layer: usize, record: SpanRecord, tracked: bool) {
+        self.0.retain(|(id, ..)| *id != layer);
+        self.0.push((layer, Arc::new(record), tracked));
+    }
 }
 
-impl StandardOutput {
-    fn new(level: &Level) -> Self {
-        match *level {
-            Level::ERROR | Level::WARN => Self::Err(io::stderr()),
-            _ => Self::Out(io::stdout()),
-        }
+/// When a span was first entered, for [`Timestamps::since_span`].
+struct Entered(Instant);
+
+/// Ids identifying a span and its trace in JSON output.
+#[derive(Debug, Copy, Clone)]
+struct TraceIds {
+    trace_id: u128,
+    span_id: u64,
+}
+
+/// Lines of a trace held back by tail sampling.
+struct TailBuffer {
+    opened: Instant,
+    error: bool,
+    records: VecDeque<LogRecord>,
+}
+
+impl TailBuffer {
+    /// The records to output now that the trace is over, counting the others as dropped.
+    fn into_kept(self, tail_sampling: &TailSampling) -> Vec<LogRecord> {
+        let slow = tail_sampling
+            .latency_threshold
+            .is_some_and(|threshold| self.opened.elapsed() > threshold);
+        let keep = self.error || slow;
+
+        let total = self.records.len();
+        let kept: Vec<_> = self
+            .records
+            .into_iter()
+            .filter(|record| keep || tail_sampling.always_keeps(&record.level))
+            .collect();
+        record_drop(DropReason::TailSampling, (total - kept.len()) as u64);
+        kept
     }
+}
 
-    fn get_dyn_ref(&mut self) -> &mut dyn io::Write {
-        match self {
-            Self::Out(out) => out,
-            Self::Err(err) => err,
+/// Buffers of the traces held back by tail sampling.
+#[derive(Default)]
+struct TailBuffers {
+    /// Keyed by root span id.
+    traces: HashMap<u64, TailBuffer>,
+    /// Records in all buffers.
+    records: usize,
+}
+
+impl TailBuffers {
+    /// Holds back `record` in the buffer of `root`, which must have one.
+    ///
+    /// Returns the oldest records pushed out of the buffers by the limits of
+    /// `tail_sampling` that must still be output, counting the others as dropped.
+    fn push(
+        &mut self,
+        root: u64,
+        record: LogRecord,
+        tail_sampling: &TailSampling,
+    ) -> Vec<LogRecord> {
+        let buffer = self.traces.get_mut(&root).unwrap();
+        buffer.error |= record.level == Level::ERROR;
+        buffer.records.push_back(record);
+        self.records += 1;
+
+        let mut evicted = Vec::new();
+        if buffer.records.len() > tail_sampling.max_per_trace {
+            evicted.extend(buffer.records.pop_front());
+        }
+        if self.records - evicted.len() > tail_sampling.max_total {
+            let oldest = self
+                .traces
+                .values_mut()
+                .filter(|buffer| !buffer.records.is_empty())
+                .min_by_key(|buffer| buffer.records[0].time);
+            evicted.extend(oldest.and_then(|buffer| buffer.records.pop_front()));
         }
+        self.records -= evicted.len();
+
+        let total = evicted.len();
+        evicted.retain(|record| tail_sampling.always_keeps(&record.level));
+        record_drop(DropReason::TailSampling, (total - evicted.len()) as u64);
+        evicted
+    }
+
+    /// Removes the buffer of the trace of `root`, once it is over.
+    fn remove(&mut self, root: u64) -> Option<TailBuffer> {
+        let buffer = self.traces.remove(&root)?;
+        self.records -= buffer.records.len();
+        Some(buffer)
     }
 }
 
+fn root_id<R>(span: &SpanRef<'_, R>) -> u64
+where
+    R: for<'span> LookupSpan<'span>,
+{
+    span.scope().last().unwrap().id().into_u64()
+}
+
 /// Output messages to standard streams.
 ///
 /// ERROR/WARN go to stderr.
 /// All others to go to stdout.
 pub struct FmtLayer {
+    /// Tells apart the data of several layers kept on the same span or thread.
+    id: usize,
     start: Instant,
+    sinks: Vec<Sink>,
+    flush: Flush,
+    format: Format,
+    options: FormatOptions,
+    /// Micros since `start` of the previous event, for [`Timestamps::delta`].
+    last_event: AtomicU64,
+    counts: Arc<EventCounts>,
+    tail_sampling: Option<TailSampling>,
+    storm_protection: Option<StormProtection>,
+    tail_buffers: Arc<Mutex<TailBuffers>>,
+    hooks: Vec<(Level, Hook)>,
+    field_mapping: FieldMapping,
+    level_mapping: LevelMapping,
+    metric_rules: MetricRules,
+    span_name_field: &'static str,
+    span_limits: SpanLimits,
+    /// Open spans with a tracked [`CachedSpans`] record from this layer.
+    tracked_spans: AtomicUsize,
+    max_level: Option<LevelFilter>,
+    drop_report_interval: Option<Duration>,
+    sink_event_level: Option<Level>,
+    /// When drops were last reported, and the counts at that time.
+    last_drop_report: Mutex<(Instant, DroppedEvents)>,
+    problem_table: Option<Arc<ProblemTable>>,
+    /// Stops the heartbeat thread when dropped.
+    heartbeat: Option<(Arc<Heartbeat>, Duration)>,
+    /// Sinks of a [`FmtLayer::with_flush_task`] task.
+    #[cfg(feature = "async-flush")]
+    flush_task: Option<crate::flush_task::FlushedSinks>,
 }
 
 impl FmtLayer {
     pub fn new() -> Self {
+        Self::with_first_sink(Sink::stdout())
+    }
+
+    /// Output all messages through `print!` and `eprint!` instead, so `cargo test` only
+    /// shows those of failing tests. Install it per test with
+    /// [`initialize_test_subscriber`](crate::initialize_test_subscriber).
+    pub fn with_test_writer() -> Self {
+        Self::with_first_sink(Sink::test())
+    }
+
+    /// Output all messages to the given file instead. The file will be cleared if it exists.
+    pub fn with_file(file: impl AsRef<Path>) -> io::Result<Self> {
+        Sink::file(file).map(Self::with_first_sink)
+    }
+
+    /// Append all messages to the given file, which several processes can share.
+    ///
+    /// Lines are written whole while holding an advisory lock on the file, so those of
+    /// different processes never get mixed up. Buffered lines are only written once the
+    /// buffer is full or flushed, and then all at once.
+    pub fn with_shared_file(file: impl AsRef<Path>) -> io::Result<Self> {
+        Sink::shared_file(file).map(Self::with_first_sink)
+    }
+
+    /// Output all messages to the given file, never letting it grow past `max_bytes`, for
+    /// devices with little storage. Lines left in it by a previous run are kept.
+    ///
+    /// Once full, the oldest lines are dropped, keeping the newest ones up to half of
+    /// `max_bytes`. This is announced by a `sink truncated, dropping oldest lines` record,
+    /// see [`FmtLayer::with_sink_events`]. Lines that alone take up half of `max_bytes` are
+    /// cut to fit, announced by a `sink line too long, cutting it` record.
+    pub fn with_capped_file(file: impl AsRef<Path>, max_bytes: u64) -> io::Result<Self> {
+        Sink::capped_file(file, max_bytes).map(Self::with_first_sink)
+    }
+
+    /// Output all messages to the given file, with every line encrypted for `recipient`.
+    ///
+    /// Read the file back with [`decrypt_log_file`](crate::decrypt_log_file).
+    #[cfg(feature = "encryption")]
+    pub fn with_encrypted_file(
+        file: impl AsRef<Path>,
+        recipient: crate::PublicKey,
+    ) -> io::Result<Self> {
+        Sink::encrypted_file(file, recipient).map(Self::with_first_sink)
+    }
+
+    /// Stream all messages into a Unix domain socket, or a named pipe like
+    /// `\\.\pipe\logs` on Windows, for a collector running next to the application.
+    ///
+    /// Connecting happens on the first message. Whenever the connection is lost, it is
+    /// retried with exponential backoff, messages in between are dropped, or kept on disk
+    /// with [`Sink::spooled_socket`].
+    #[cfg(any(unix, windows))]
+    pub fn with_socket(path: impl Into<std::path::PathBuf>) -> Self {
+        Self::with_first_sink(Sink::socket(path))
+    }
+
+    /// Keep the last `capacity` lines in memory instead, for the returned viewer to show.
+    ///
+    /// ```no_run
+    /// # use tracing_subscriber::layer::SubscriberExt as _;
+    /// let (layer, mut viewer) = wgpu_subscriber::FmtLayer::with_tui(10_000);
+    /// tracing::subscriber::set_global_default(tracing_subscriber::Registry::default().with(layer))
+    ///     .unwrap();
+    /// std::thread::spawn(move || viewer.run());
+    /// ```
+    #[cfg(feature = "tui")]
+    pub fn with_tui(capacity: usize) -> (Self, crate::TuiViewer) {
+        let (sink, viewer) = Sink::tui(capacity);
+        (Self::with_first_sink(sink), viewer)
+    }
+
+    /// Keep all messages in memory instead, formatted the same way on every run, for
+    /// snapshot tests. Times are [mocked](Timestamps::mock), fields sorted by name, and
+    /// records about sinks and dropped events left out.
+    ///
+    /// See [`capture_snapshot`](crate::capture_snapshot) to capture what a closure logs.
+    pub fn with_snapshot() -> (Self, crate::Snapshot) {
+        let (sink, snapshot) = Sink::snapshot();
+        let layer = Self::with_first_sink(sink)
+            .with_timestamps(Timestamps::new().mock(true))
+            .with_field_order(FieldOrder::Alphabetical)
+            .with_sink_events(None)
+            .with_drop_reports(None);
+        (layer, snapshot)
+    }
+
+    fn with_first_sink(sink: Sink) -> Self {
+        crate::run_id();
+        if let Some(Flush::Interval(interval)) = sink.flush {
+            writer::set_flusher(Arc::downgrade(&sink.output), Some(interval));
+        }
         FmtLayer {
+            id: NEXT_LAYER_ID.fetch_add(1, Ordering::Relaxed),
             start: Instant::now(),
+            sinks: vec![sink],
+            flush: Flush::Line,
+            format: Format::Text,
+            options: FormatOptions::default(),
+            last_event: AtomicU64::new(0),
+            counts: Arc::default(),
+            tail_sampling: None,
+            storm_protection: None,
+            tail_buffers: Arc::default(),
+            hooks: Vec::new(),
+            field_mapping: FieldMapping::new(),
+            level_mapping: LevelMapping::new(),
+            metric_rules: MetricRules::new(),
+            span_name_field: "otel.name",
+            span_limits: SpanLimits::new(),
+            tracked_spans: AtomicUsize::new(0),
+            max_level: None,
+            drop_report_interval: Some(Duration::from_secs(10)),
+            sink_event_level: Some(Level::INFO),
+            last_drop_report: Mutex::new((Instant::now(), dropped_events())),
+            heartbeat: None,
+            problem_table: None,
+            #[cfg(feature = "async-flush")]
+            flush_task: None,
+        }
+    }
+
+    /// A guard flushing the output when dropped, which can also output a summary of the
+    /// whole run, see [`FmtGuard::with_summary`].
+    ///
+    /// Get it after configuring the format, it keeps the one set at this point.
+    ///
+    /// ```no_run
+    /// # use tracing_subscriber::layer::SubscriberExt as _;
+    /// # use wgpu_subscriber::FmtLayer;
+    /// let layer = FmtLayer::new();
+    /// let _guard = layer.guard().with_summary();
+    /// tracing::subscriber::set_global_default(tracing_subscriber::Registry::default().with(layer))
+    ///     .unwrap();
+    /// ```
+    pub fn guard(&self) -> FmtGuard {
+        let drain = self.tail_sampling.clone().map(|tail_sampling| {
+            let tail_buffers = Arc::downgrade(&self.tail_buffers);
+            move || match tail_buffers.upgrade() {
+                Some(buffers) => {
+                    let buffers = std::mem::take(&mut *buffers.lock());
+                    let mut buffers: Vec<_> = buffers.traces.into_values().collect();
+                    buffers.sort_by_key(|buffer| buffer.opened);
+                    buffers
+                        .into_iter()
+                        .flat_map(|buffer| buffer.into_kept(&tail_sampling))
+                        .collect()
+                }
+                None => Vec::new(),
+            }
+        });
+        FmtGuard::new(
+            self.start,
+            self.sinks.clone(),
+            self.format.clone(),
+            self.options.clone(),
+            self.counts.clone(),
+            drain,
+            self.heartbeat
+                .as_ref()
+                .map(|(heartbeat, _)| Arc::downgrade(heartbeat)),
+        )
+        .with_problem_table(self.problem_table.clone())
+    }
+
+    /// How often to output a line like `42 events dropped (write errors)` when events
+    /// were lost since the last one. Defaults to every 10 seconds, `None` disables it.
+    ///
+    /// The counts are also available from [`dropped_events`](crate::dropped_events).
+    pub fn with_drop_reports(mut self, interval: Option<Duration>) -> Self {
+        self.drop_report_interval = interval;
+        self
+    }
+
+    /// Also output to `sink`, in its own format and level if it has them.
+    ///
+    /// Every event is visited once, and formatted once per format.
+    pub fn with_sink(mut self, sink: Sink) -> Self {
+        if let Flush::Interval(interval) = sink.flush.unwrap_or(self.flush) {
+            writer::set_flusher(Arc::downgrade(&sink.output), Some(interval));
+        }
+        #[cfg(feature = "async-flush")]
+        if let Some(ref sinks) = self.flush_task {
+            sinks.lock().push(sink.clone());
+        }
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Level of the records about the health of sinks, INFO by default, `None` disables
+    /// them. These are lines like `sink disconnected`, `sink spooling lines`, `sink
+    /// replayed spooled lines`, `sink connected` or `sink truncated, dropping oldest lines`,
+    /// with the sink as field.
+    pub fn with_sink_events(mut self, level: Option<Level>) -> Self {
+        self.sink_event_level = level;
+        self
+    }
+
+    /// Format records with `format`, [`Format::Text`] by default. Sinks with their own
+    /// format keep it.
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Render errors and multi-line `Debug` values, like an `anyhow::Error` or
+    /// `eyre::Report`, in full on indented lines below the line in [`Format::Text`],
+    /// instead of inline. Errors recorded as `&dyn Error` are followed by their sources.
+    ///
+    /// ```
+    /// # use std::{error::Error, fmt, io};
+    /// # use tracing_subscriber::layer::SubscriberExt as _;
+    /// # #[derive(Debug)]
+    /// # struct SaveError(io::Error);
+    /// # impl fmt::Display for SaveError {
+    /// #     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    /// #         f.write_str("can't write save.dat")
+    /// #     }
+    /// # }
+    /// # impl Error for SaveError {
+    /// #     fn source(&self) -> Option<&(dyn Error + 'static)> {
+    /// #         Some(&self.0)
+    /// #     }
+    /// # }
+    /// let (layer, snapshot) = wgpu_subscriber::FmtLayer::with_snapshot();
+    /// let layer = layer.with_error_reports(true);
+    /// tracing::subscriber::with_default(tracing_subscriber::Registry::default().with(layer), || {
+    ///     let err = SaveError(io::Error::other("disk full"));
+    ///     tracing::error!(error = &err as &dyn Error, "failed to save");
+    /// });
+    /// assert!(snapshot.take().ends_with(
+    ///     ": failed to save error=can't write save.dat
+    ///     error: can't write save.dat
+    ///       caused by: disk full\n",
+    /// ));
+    /// ```
+    pub fn with_error_reports(mut self, enabled: bool) -> Self {
+        self.options.error_reports = enabled;
+        self
+    }
+
+    /// Add a `run_id` field to every record, holding the [`run_id`](crate::run_id) of the
+    /// process, so lines of different runs written to the same output can be told apart.
+    /// Records that already have one, like those [replayed](FmtLayer::replay), keep theirs.
+    pub fn with_run_id(mut self, enabled: bool) -> Self {
+        self.options.run_id = enabled;
+        self
+    }
+
+    /// Order of the fields in the output, [`FieldOrder::Definition`] by default.
+    pub fn with_field_order(mut self, order: FieldOrder) -> Self {
+        self.options.field_order = order;
+        self
+    }
+
+    /// Which times to show in [`Format::Text`], [`Timestamps::new`] by default.
+    pub fn with_timestamps(mut self, timestamps: Timestamps) -> Self {
+        self.options.timestamps = timestamps;
+        self
+    }
+
+    /// Show levels and wall clock times in [`Format::Text`] as `localization` says, in the
+    /// language of the user.
+    pub fn with_localization(mut self, localization: Localization) -> Self {
+        self.options.localization = Some(Arc::new(localization));
+        self
+    }
+
+    /// When to flush buffered output, [`Flush::Line`] by default. Sinks with their own
+    /// [flush](Sink::with_flush) keep it.
+    pub fn with_flush(mut self, flush: Flush) -> Self {
+        for sink in self.sinks.iter().filter(|sink| sink.flush.is_none()) {
+            let interval = match flush {
+                Flush::Interval(interval) => Some(interval),
+                _ => None,
+            };
+            writer::set_flusher(Arc::downgrade(&sink.output), interval);
+        }
+        self.flush = flush;
+        self
+    }
+
+    /// Buffer output and flush it every `interval` from a task on the current tokio
+    /// runtime, instead of a thread like [`Flush::Interval`].
+    ///
+    /// Await [`FlushTask::shutdown`](crate::FlushTask::shutdown) before the runtime
+    /// stops, so the last lines are flushed.
+    ///
+    /// # Panics
+    ///
+    /// When called outside of a tokio runtime.
+    #[cfg(feature = "async-flush")]
+    pub fn with_flush_task(mut self, interval: Duration) -> (Self, crate::FlushTask) {
+        let sinks = Arc::new(Mutex::new(self.sinks.clone()));
+        let task = crate::FlushTask::spawn(sinks.clone(), interval);
+        self.flush = Flush::Buffered;
+        self.flush_task = Some(sinks);
+        (self, task)
+    }
+
+    /// Rename and rewrite fields of every event before hooks and output see them.
+    pub fn with_field_mapping(mut self, mapping: FieldMapping) -> Self {
+        self.field_mapping = mapping;
+        self
+    }
+
+    /// Change the level of events of some targets before metrics, hooks, tail sampling and
+    /// the levels of sinks see them.
+    ///
+    /// Filters deciding per callsite, like [`FmtLayer::with_max_level`] or an
+    /// [`EnvFilter`](tracing_subscriber::EnvFilter), still see the original level, so an
+    /// event promoted from DEBUG is only output if DEBUG is enabled for its target.
+    pub fn with_level_mapping(mut self, mapping: LevelMapping) -> Self {
+        self.level_mapping = mapping;
+        self
+    }
+
+    /// Name of the span field that, when recorded, replaces the name of its span in the
+    /// scope of every line, `otel.name` by default.
+    ///
+    /// ```
+    /// # let path = "/index.html";
+    /// let span = tracing::info_span!("request", otel.name = tracing::field::Empty);
+    /// span.record("otel.name", &path);
+    /// ```
+    pub fn with_span_name_field(mut self, field: &'static str) -> Self {
+        self.span_name_field = field;
+        self
+    }
+
+    /// Limit the memory used for the fields of open spans, [`SpanLimits::new`] by default.
+    pub fn with_span_limits(mut self, limits: SpanLimits) -> Self {
+        self.span_limits = limits;
+        self
+    }
+
+    /// Only output events at `level` or above.
+    ///
+    /// The decision is cached per callsite, so filtered out events cost next to nothing.
+    /// Like [`EnvFilter`](tracing_subscriber::EnvFilter), this filters the whole subscriber
+    /// and not only this layer.
+    pub fn with_max_level(mut self, level: impl Into<LevelFilter>) -> Self {
+        self.max_level = Some(level.into());
+        self
+    }
+
+    /// Call `hook` with every event at `level` or above, before it is output.
+    ///
+    /// Hooks see every event, including those later held back by tail sampling. Events
+    /// logged from inside a hook are output as usual, but don't run any hooks.
+    pub fn with_hook(
+        mut self,
+        level: Level,
+        hook: impl Fn(&LogRecord) + Send + Sync + 'static,
+    ) -> Self {
+        self.hooks.push((level, Box::new(hook)));
+        self
+    }
+
+    /// Call `hook` with every ERROR event. See [`FmtLayer::with_hook`].
+    pub fn on_error(self, hook: impl Fn(&LogRecord) + Send + Sync + 'static) -> Self {
+        self.with_hook(Level::ERROR, hook)
+    }
+
+    /// Call `hook` with every WARN or ERROR event. See [`FmtLayer::with_hook`].
+    pub fn on_warn(self, hook: impl Fn(&LogRecord) + Send + Sync + 'static) -> Self {
+        self.with_hook(Level::WARN, hook)
+    }
+
+    /// Hold back all events of a trace until its root span closes, and only output
+    /// them if the trace is interesting. Events outside of any span are output
+    /// immediately.
+    pub fn with_tail_sampling(mut self, tail_sampling: TailSampling) -> Self {
+        self.tail_sampling = Some(tail_sampling);
+        self
+    }
+
+    /// Only output WARN and ERROR events while more events than
+    /// [`StormProtection`] allows arrive, to protect production from runaway loops.
+    ///
+    /// Like [`FmtLayer::with_max_level`], this filters the whole subscriber. Events can no
+    /// longer be filtered once per callsite, so this costs a little on each event.
+    pub fn with_storm_protection(mut self, storm_protection: StormProtection) -> Self {
+        self.storm_protection = Some(storm_protection);
+        self
+    }
+
+    /// Update the counters and histograms of `rules` with every event. Can be called
+    /// several times to add more rules.
+    pub fn with_metrics(mut self, rules: MetricRules) -> Self {
+        self.metric_rules.extend(rules);
+        self
+    }
+
+    /// Output a `heartbeat` record at INFO every `interval` from a background thread, so
+    /// monitoring can tell a quiet process from a dead one or a broken pipeline. It has the
+    /// uptime, the number of events, errors, warnings and dropped events since the previous
+    /// one, and how many traces and events tail sampling holds back.
+    ///
+    /// Call it after adding sinks and configuring the format, it keeps those set at this
+    /// point. The thread stops once the layer is dropped.
+    pub fn with_heartbeat(mut self, interval: Duration) -> Self {
+        let tail_buffers = Arc::downgrade(&self.tail_buffers);
+        let held_back = move || match tail_buffers.upgrade() {
+            Some(buffers) => {
+                let buffers = buffers.lock();
+                (buffers.traces.len() as u64, buffers.records as u64)
+            }
+            None => (0, 0),
+        };
+        let heartbeat = Arc::new(Heartbeat::new(
+            self.start,
+            self.sinks.clone(),
+            self.format.clone(),
+            self.options.clone(),
+            self.counts.clone(),
+            held_back,
+        ));
+        Heartbeat::spawn(Arc::downgrade(&heartbeat), interval);
+        self.heartbeat = Some((heartbeat, interval));
+        self
+    }
+
+    /// Count warnings and errors per target or message, and print them as a table to
+    /// stderr when the [guard](FmtLayer::guard) is dropped, so users of command line tools
+    /// notice problems that scrolled past. Nothing is printed if there were none.
+    ///
+    /// ```text
+    /// warnings and errors:
+    ///   LEVEL  COUNT  TARGET
+    ///   ERROR      2  payments::card
+    ///   WARN      13  hyper
+    /// ```
+    ///
+    /// Get the guard after calling this.
+    pub fn with_problem_table(mut self, group_by: GroupBy) -> Self {
+        self.problem_table = Some(Arc::new(ProblemTable::new(group_by)));
+        self
+    }
+
+    /// Check the configuration, so mistakes show up at startup rather than as missing
+    /// output. Use it last, errors opening files convert to [`BuildError::Io`] so `?` works
+    /// for both.
+    ///
+    /// ```no_run
+    /// # use wgpu_subscriber::{BuildError, Csv, FmtLayer, Format};
+    /// let layer = FmtLayer::with_file("app.csv")?
+    ///     .with_format(Format::Csv(Csv::new().with_delimiter(';')))
+    ///     .build()?;
+    /// # Ok::<(), BuildError>(())
+    /// ```
+    pub fn build(self) -> Result<Self, BuildError> {
+        if self.flush == Flush::Interval(Duration::ZERO) {
+            return Err(BuildError::FlushInterval);
         }
+        if let Some((_, Duration::ZERO)) = self.heartbeat {
+            return Err(BuildError::HeartbeatInterval);
+        }
+        if let Format::Csv(ref csv) = self.format {
+            csv.check()?;
+        }
+        for sink in &self.sinks {
+            sink.check(&self.format)?;
+        }
+        if self
+            .storm_protection
+            .as_ref()
+            .is_some_and(|storm| storm.events_per_second() == 0)
+        {
+            return Err(BuildError::StormLimit);
+        }
+        Ok(self)
+    }
+
+    /// Output a record read back with [`replay`](crate::replay) as if it was an event,
+    /// keeping its times and spans.
+    ///
+    /// It goes through the max level, level mapping, metrics, field mapping, field order and
+    /// hooks of this layer, like events do, but not through filters of the subscriber.
+    pub fn replay(&self, record: &LogRecord) {
+        if self
+            .max_level
+            .is_some_and(|max_level| record.level > max_level)
+        {
+            return;
+        }
+        let mut record = Cow::Borrowed(record);
+        if !self.level_mapping.is_empty() {
+            self.level_mapping.apply(record.to_mut());
+        }
+        self.counts.count(&record.level);
+        if let Some(ref table) = self.problem_table {
+            table.count(&record);
+        }
+        if !self.metric_rules.is_empty() {
+            self.metric_rules.apply(&record);
+        }
+
+        if !self.field_mapping.is_empty() {
+            self.field_mapping.apply(&mut record.to_mut().fields);
+        }
+        if self.options.field_order == FieldOrder::Alphabetical {
+            record.to_mut().fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        self.run_hooks(&record);
+        self.write_record(&record);
+    }
+
+    fn run_hooks(&self, record: &LogRecord) {
+        if self.hooks.is_empty() || IN_HOOK.with(Cell::get) {
+            return;
+        }
+
+        IN_HOOK.with(|in_hook| in_hook.set(true));
+        for (level, hook) in &self.hooks {
+            if record.level <= *level {
+                hook(record);
+            }
+        }
+        IN_HOOK.with(|in_hook| in_hook.set(false));
+    }
+
+    /// Merges the fields collected by `visitor` into `span` and renders it again.
+    fn update_span(&self, span: &mut SpanRecord, visitor: SpanFieldVisitor) {
+        if let Some(ref name) = visitor.display_name {
+            span.name = Cow::Owned(name.clone());
+        }
+        let mut fields = visitor.into_fields().collect();
+        if !self.field_mapping.is_empty() {
+            self.field_mapping.apply(&mut fields);
+        }
+        span.merge(fields, &self.span_limits);
+        if self.options.field_order == FieldOrder::Alphabetical {
+            span.sort_fields();
+        }
+        span.render();
+    }
+
+    /// Formats and writes `record` to all sinks, reusing a per thread buffer for the line.
+    fn write_record(&self, record: &LogRecord) {
+        let write = |line: &mut String| {
+            let mut events = Vec::new();
+            self.write_to_sinks(record, line, &mut events);
+
+            let level = match self.sink_event_level {
+                Some(level) if self.max_level.is_none_or(|max_level| level <= max_level) => level,
+                _ => return,
+            };
+            // Writing these can cause events of its own, like a disconnection.
+            while !events.is_empty() {
+                let time = self.start.elapsed();
+                for event in std::mem::take(&mut events) {
+                    self.write_to_sinks(&event.into_record(time, level), line, &mut events);
+                }
+            }
+        };
+        LINE_BUFFER.with(|buffer| match buffer.try_borrow_mut() {
+            Ok(mut line) => write(&mut line),
+            Err(_) => write(&mut String::new()),
+        });
+    }
+
+    fn write_to_sinks(&self, record: &LogRecord, line: &mut String, events: &mut Vec<SinkEvent>) {
+        writer::write_record(
+            &self.sinks,
+            &self.format,
+            &self.options,
+            self.flush,
+            record,
+            line,
+            events,
+        );
+    }
+
+    /// Counts an event at `level` and whether it is output during a storm.
+    fn check_storm(&self, storm: &StormProtection, level: &Level) -> bool {
+        let time = self.start.elapsed();
+        let record = match storm.observe(time) {
+            Some(StormChange::Started { events_per_second }) => {
+                let mut record = LogRecord::internal(
+                    time,
+                    Level::WARN,
+                    "log storm, only outputting WARN and above".to_owned(),
+                );
+                record.fields.extend([
+                    (Cow::Borrowed("events_per_second"), events_per_second.into()),
+                    (Cow::Borrowed("limit"), storm.events_per_second().into()),
+                ]);
+                Some(record)
+            }
+            Some(StormChange::Ended { suppressed }) => {
+                let mut record = LogRecord::internal(
+                    time,
+                    Level::WARN,
+                    "log storm over, outputting all levels again".to_owned(),
+                );
+                record
+                    .fields
+                    .push((Cow::Borrowed("suppressed"), suppressed.into()));
+                Some(record)
+            }
+            None => None,
+        };
+        if let Some(record) = record {
+            self.write_record(&record);
+        }
+
+        if storm.is_degraded() && *level > Level::WARN {
+            storm.suppress();
+            record_drop(DropReason::StormProtection, 1);
+            return false;
+        }
+        true
+    }
+
+    fn report_drops(&self, now: Instant) {
+        let interval = match self.drop_report_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+
+        let dropped = dropped_events();
+        let mut last = match self.last_drop_report.try_lock() {
+            Some(last) => last,
+            None => return,
+        };
+        if dropped == last.1 || now - last.0 < interval {
+            return;
+        }
+
+        let since = dropped.since(&last.1);
+        *last = (now, dropped);
+        drop(last);
+
+        let record = LogRecord::internal(now - self.start, Level::WARN, since.describe());
+        self.write_record(&record);
+    }
+}
+
+impl Default for FmtLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A mistake in the configuration of a [`FmtLayer`], see [`FmtLayer::build`].
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// A file or socket couldn't be opened.
+    Io(io::Error),
+    /// [`Flush::Interval`] is zero.
+    FlushInterval,
+    /// The interval of [`FmtLayer::with_heartbeat`] is zero.
+    HeartbeatInterval,
+    /// The period of a [`Sink::with_quota`] is zero.
+    QuotaPeriod,
+    /// A [`Csv`](crate::Csv) delimiter is a quote or a line break.
+    CsvDelimiter(char),
+    /// A sink writing MessagePack or protobuf has an output taking only text, like a
+    /// [`Sink::capped_file`].
+    BinaryEncoding(&'static str),
+    /// [`StormProtection`] allows no events at all.
+    StormLimit,
+    /// A [`Query`](crate::Query) couldn't be parsed.
+    Query(crate::QueryError),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Io(_) => f.write_str("failed to open an output"),
+            BuildError::FlushInterval => f.write_str("flush interval is zero"),
+            BuildError::HeartbeatInterval => f.write_str("heartbeat interval is zero"),
+            BuildError::QuotaPeriod => f.write_str("sink quota period is zero"),
+            BuildError::CsvDelimiter(delimiter) => {
+                write!(f, "{:?} can't be a CSV delimiter", delimiter)
+            }
+            BuildError::BinaryEncoding(output) => {
+                write!(f, "a {} sink only takes text", output)
+            }
+            BuildError::StormLimit => f.write_str("storm protection allows no events"),
+            BuildError::Query(_) => f.write_str("invalid sink query"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BuildError::Io(err) => Some(err),
+            BuildError::Query(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BuildError {
+    fn from(err: io::Error) -> Self {
+        BuildError::Io(err)
+    }
+}
+
+impl From<crate::QueryError> for BuildError {
+    fn from(err: crate::QueryError) -> Self {
+        BuildError::Query(err)
     }
 }
 
@@ -60,44 +1023,303 @@ impl<S> Layer<S> for FmtLayer
 where
     S: Subscriber + for<'span> LookupSpan<'span>,
 {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if !crate::statically_enabled(metadata.level()) {
+            return Interest::never();
+        }
+        match self.max_level {
+            Some(max_level) if *metadata.level() > max_level => Interest::never(),
+            // Every event has to be counted.
+            _ if self.storm_protection.is_some() && metadata.is_event() => Interest::sometimes(),
+            _ => Interest::always(),
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        if self
+            .max_level
+            .is_some_and(|max_level| *metadata.level() > max_level)
+        {
+            return false;
+        }
+        match self.storm_protection {
+            Some(ref storm) if metadata.is_event() => self.check_storm(storm, metadata.level()),
+            _ => true,
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.max_level
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).unwrap();
+
+        // Another layer of this kind may already have assigned ids.
+        if span.extensions().get::<TraceIds>().is_none() {
+            let trace_id = span
+                .parent()
+                .and_then(|parent| {
+                    parent
+                        .extensions()
+                        .get::<TraceIds>()
+                        .map(|ids| ids.trace_id)
+                })
+                .unwrap_or_else(|| u128::from(random_u64()) << 64 | u128::from(random_u64()));
+            span.extensions_mut().insert(TraceIds {
+                trace_id,
+                span_id: random_u64(),
+            });
+        }
+
+        let mut visitor = SpanFieldVisitor::new(self.span_name_field);
+        attrs.record(&mut visitor);
+        let error = visitor.error;
+
+        let name = match visitor.display_name.take() {
+            Some(name) => Cow::Owned(name),
+            None => Cow::Borrowed(span.name()),
+        };
+        let tracked = self
+            .tracked_spans
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |tracked| {
+                (tracked < self.span_limits.max_tracked_spans).then_some(tracked + 1)
+            })
+            .is_ok();
+        let record = if tracked {
+            let mut record = SpanRecord::new(name);
+            self.update_span(&mut record, visitor);
+            record
+        } else {
+            SpanRecord::untracked(name)
+        };
+        let mut extensions = span.extensions_mut();
+        match extensions.get_mut::<CachedSpans>() {
+            Some(cached) => cached.insert(self.id, record, tracked),
+            None => {
+                let mut cached = CachedSpans::default();
+                cached.insert(self.id, record, tracked);
+                extensions.insert(cached);
+            }
+        }
+        drop(extensions);
+
+        if self.tail_sampling.is_none() {
+            return;
+        }
+
+        // Only root spans get a buffer, it is removed when they close.
+        let mut buffers = self.tail_buffers.lock();
+        if span.parent().is_none() {
+            buffers.traces.insert(
+                id.into_u64(),
+                TailBuffer {
+                    opened: Instant::now(),
+                    error,
+                    records: VecDeque::new(),
+                },
+            );
+        } else if let Some(buffer) = buffers.traces.get_mut(&root_id(&span)) {
+            buffer.error |= error;
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let span = ctx.span(id).unwrap();
+
+        let mut visitor = SpanFieldVisitor::new(self.span_name_field);
+        values.record(&mut visitor);
+        let error = visitor.error;
+
+        let mut extensions = span.extensions_mut();
+        let cached = extensions.get_mut::<CachedSpans>();
+        if let Some(record) = cached.and_then(|cached| cached.tracked_mut(self.id)) {
+            self.update_span(Arc::make_mut(record), visitor);
+        }
+        drop(extensions);
+
+        if self.tail_sampling.is_none() || !error {
+            return;
+        }
+
+        let root = root_id(&span);
+        if let Some(buffer) = self.tail_buffers.lock().traces.get_mut(&root) {
+            buffer.error = true;
+        }
+    }
+
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if !crate::statically_enabled(event.metadata().level()) {
+            return;
+        }
+
         let now = Instant::now();
         let time = now - self.start;
 
-        let mut visitor = FmtEventVisitor::default();
-        event.record(&mut visitor);
+        self.report_drops(now);
 
-        let mut span_string = String::new();
-        for span in ctx.scope() {
-            if !span_string.is_empty() {
-                span_string.push_str(" | ");
+        let mut visitor = RecordVisitor::default();
+        event.record(&mut visitor);
+        #[cfg(feature = "task-context")]
+        crate::TaskContext::with_current(|context| {
+            for (name, value) in context {
+                if !visitor.fields.iter().any(|(field, _)| field == name) {
+                    visitor.fields.push((name.clone(), value.clone()));
+                }
             }
-            span_string.push_str(span.name());
+        });
+
+        let current = ctx.lookup_current();
+
+        let mut spans = Vec::new();
+        if let Some(scope) = current.as_ref().map(|span| span.scope().from_root()) {
+            spans.extend(scope.map(|span| {
+                let extensions = span.extensions();
+                match extensions.get::<CachedSpans>().and_then(|c| c.get(self.id)) {
+                    Some((record, _)) => record.clone(),
+                    None => Arc::new(SpanRecord::new(Cow::Borrowed(span.name()))),
+                }
+            }));
         }
 
+        let ids = current
+            .as_ref()
+            .and_then(|span| span.extensions().get::<TraceIds>().copied());
+
+        let delta = match self.options.timestamps.delta {
+            true => {
+                let micros = time.as_micros() as u64;
+                let scope = self.options.timestamps.delta_scope;
+                let previous = match (scope, &current) {
+                    (DeltaScope::Global, _) => {
+                        Some(self.last_event.swap(micros, Ordering::Relaxed))
+                    }
+                    (DeltaScope::Span, Some(span)) => {
+                        let mut extensions = span.extensions_mut();
+                        match extensions.get_mut::<LastEvent>() {
+                            Some(last) => last.swap(self.id, micros),
+                            None => {
+                                let mut last = LastEvent::default();
+                                last.swap(self.id, micros);
+                                extensions.insert(last);
+                                None
+                            }
+                        }
+                    }
+                    (DeltaScope::Thread, _) | (DeltaScope::Span, None) => {
+                        LAST_EVENT.with(|last| last.borrow_mut().swap(self.id, micros))
+                    }
+                };
+                previous.map(|previous| Duration::from_micros(micros.saturating_sub(previous)))
+            }
+            false => None,
+        };
+
+        let since_span = self.options.timestamps.since_span.and_then(|anchor| {
+            let mut scope = current.as_ref()?.scope();
+            let span = match anchor {
+                SpanAnchor::Innermost => scope.next()?,
+                SpanAnchor::Root => scope.last()?,
+            };
+            let extensions = span.extensions();
+            let Entered(entered) = extensions.get::<Entered>()?;
+            Some(now.saturating_duration_since(*entered))
+        });
+
         let metadata = event.metadata();
-        let level = match *metadata.level() {
-            Level::ERROR => "ERROR",
-            Level::WARN => "WARN",
-            Level::INFO => "INFO",
-            Level::DEBUG => "DEBUG",
-            Level::TRACE => "TRACE",
+        let mut record = LogRecord {
+            time,
+            timestamp: SystemTime::now(),
+            delta,
+            since_span,
+            level: *metadata.level(),
+            target: Cow::Borrowed(metadata.target()),
+            module: metadata.module_path().map(Cow::Borrowed),
+            file: metadata.file().map(Cow::Borrowed),
+            line: metadata.line(),
+            spans,
+            message: visitor.message,
+            fields: visitor.fields,
+            trace_id: ids.map(|ids| ids.trace_id),
+            span_id: ids.map(|ids| ids.span_id),
         };
+        if self.options.run_id && record.field("run_id").is_none() {
+            let run_id = FieldValue::Str(crate::run_id().to_owned());
+            record.fields.push((Cow::Borrowed("run_id"), run_id));
+        }
 
-        let module = metadata.module_path().unwrap_or("no module");
+        if !self.level_mapping.is_empty() {
+            self.level_mapping.apply(&mut record);
+        }
+        self.counts.count(&record.level);
+        if let Some(ref table) = self.problem_table {
+            table.count(&record);
+        }
+        if !self.metric_rules.is_empty() {
+            self.metric_rules.apply(&record);
+        }
+        if !self.field_mapping.is_empty() {
+            self.field_mapping.apply(&mut record.fields);
+        }
+        if self.options.field_order == FieldOrder::Alphabetical {
+            record.fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
 
-        let mut output = StandardOutput::new(metadata.level());
-        let output_ref = output.get_dyn_ref();
+        self.run_hooks(&record);
 
-        writeln!(
-            output_ref,
-            "[{:.6} {}]({})({}): {}",
-            time.as_secs_f64(),
-            level,
-            span_string,
-            module,
-            visitor.message,
-        )
-        .unwrap();
+        if let (Some(tail_sampling), Some(current)) = (&self.tail_sampling, current) {
+            let root = root_id(&current);
+            let mut buffers = self.tail_buffers.lock();
+            if buffers.traces.contains_key(&root) {
+                let evicted = buffers.push(root, record, tail_sampling);
+                drop(buffers);
+                for record in evicted {
+                    self.write_record(&record);
+                }
+                return;
+            }
+        }
+
+        self.write_record(&record);
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        if self.options.timestamps.since_span.is_none() {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            if extensions.get_mut::<Entered>().is_none() {
+                extensions.insert(Entered(Instant::now()));
+            }
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            let extensions = span.extensions();
+            let cached = extensions.get::<CachedSpans>();
+            if let Some((_, true)) = cached.and_then(|cached| cached.get(self.id)) {
+                let _ = self.tracked_spans.fetch_update(
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                    |tracked| tracked.checked_sub(1),
+                );
+            }
+        }
+
+        let tail_sampling = match self.tail_sampling {
+            Some(ref tail_sampling) => tail_sampling,
+            None => return,
+        };
+
+        // Only root spans have a buffer.
+        let buffer = match self.tail_buffers.lock().remove(id.into_u64()) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        for record in buffer.into_kept(tail_sampling) {
+            self.write_record(&record);
+        }
     }
 }
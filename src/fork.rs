@@ -0,0 +1,141 @@
+//! Background threads of the layers, restarted in the child of a `fork`.
+
+use crate::{heartbeat::Heartbeat, writer::Output};
+use parking_lot::{const_mutex, Mutex};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Every worker spawned.
+static WORKERS: Mutex<Vec<Entry>> = const_mutex(Vec::new());
+
+struct Entry {
+    /// Id of the process the thread runs in.
+    pid: u32,
+    worker: Worker,
+    interval: Duration,
+    /// Stops the thread after its next run, once the worker is replaced.
+    stop: Arc<AtomicBool>,
+}
+
+/// What a background thread does every interval, until its target is dropped.
+#[derive(Clone)]
+pub(crate) enum Worker {
+    Flusher(Weak<Output>),
+    Heartbeat(Weak<Heartbeat>),
+}
+
+impl Worker {
+    fn is_alive(&self) -> bool {
+        match self {
+            Worker::Flusher(output) => output.strong_count() != 0,
+            Worker::Heartbeat(heartbeat) => heartbeat.strong_count() != 0,
+        }
+    }
+
+    /// Whether both run for the same target.
+    fn same_target(&self, other: &Worker) -> bool {
+        match (self, other) {
+            (Worker::Flusher(a), Worker::Flusher(b)) => a.ptr_eq(b),
+            (Worker::Heartbeat(a), Worker::Heartbeat(b)) => a.ptr_eq(b),
+            _ => false,
+        }
+    }
+
+    /// Runs once, returning whether the target is still there.
+    fn run(&self) -> bool {
+        match self {
+            Worker::Flusher(output) => output.upgrade().map(|output| {
+                let _ = output.flush();
+            }),
+            Worker::Heartbeat(heartbeat) => heartbeat.upgrade().map(|heartbeat| heartbeat.beat()),
+        }
+        .is_some()
+    }
+
+    fn start(self, interval: Duration, stop: Arc<AtomicBool>) {
+        let name = match self {
+            Worker::Flusher(_) => "wgpu-subscriber flush",
+            Worker::Heartbeat(_) => "wgpu-subscriber heartbeat",
+        };
+        thread::Builder::new()
+            .name(name.into())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                if stop.load(Ordering::Relaxed) || !self.run() {
+                    break;
+                }
+            })
+            .expect("failed to spawn background thread");
+    }
+}
+
+/// Runs `worker` every `interval` on a thread of its own, which is restarted by
+/// [`after_fork`]. A worker already running for the same target is stopped, so `None` only
+/// stops it.
+pub(crate) fn spawn_worker(worker: Worker, interval: Option<Duration>) {
+    let mut workers = WORKERS.lock();
+    workers.retain(|entry| {
+        let replaced = entry.worker.same_target(&worker);
+        if replaced {
+            entry.stop.store(true, Ordering::Relaxed);
+        }
+        !replaced && entry.worker.is_alive()
+    });
+    // Rejected by `FmtLayer::build`, it would only keep a core busy.
+    let interval = match interval {
+        Some(interval) if !interval.is_zero() => interval,
+        _ => return,
+    };
+    let stop = Arc::new(AtomicBool::new(false));
+    workers.push(Entry {
+        pid: std::process::id(),
+        worker: worker.clone(),
+        interval,
+        stop: stop.clone(),
+    });
+    drop(workers);
+    worker.start(interval, stop);
+}
+
+/// Restarts the background threads of every layer in the child of a `fork`, which only
+/// keeps the thread that forked. Call it in the child right after forking, before it logs,
+/// for example after daemonizing. Without it, [`Flush::Interval`](crate::Flush::Interval)
+/// output is only flushed once buffers fill up, and
+/// [`FmtLayer::with_heartbeat`](crate::FmtLayer::with_heartbeat) stops.
+///
+/// Threads already running in this process are left alone, so calling it more than once,
+/// or in the parent, does nothing.
+///
+/// Sockets, see [`Sink::socket`](crate::Sink::socket), notice the fork on their own and
+/// connect again, instead of sharing the connection of the parent. Lines still buffered
+/// when forking are written by both processes, flush them before, and a
+/// [`FlushTask`](crate::FlushTask) has to be created again on a new runtime.
+///
+/// # Locks
+///
+/// Locks held by other threads when forking stay locked in the child, which deadlocks the
+/// first time it writes to a sink another thread was writing to, or calls this while
+/// another thread was starting a background thread. Only fork while no other thread logs
+/// or builds a layer. The threads of `Flush::Interval` and heartbeats take these locks
+/// too while they run, so a process using them should fork before they start.
+pub fn after_fork() {
+    let pid = std::process::id();
+    let mut workers = WORKERS.lock();
+    workers.retain(|entry| entry.worker.is_alive());
+    for entry in workers.iter_mut() {
+        if entry.pid != pid {
+            entry.pid = pid;
+            entry.stop = Arc::new(AtomicBool::new(false));
+            entry
+                .worker
+                .clone()
+                .start(entry.interval, entry.stop.clone());
+        }
+    }
+}
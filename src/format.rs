@@ -0,0 +1,640 @@
+use crate::{bare, json, Csv, FieldValue, LogRecord};
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::{
+    borrow::Cow,
+    fmt::{self, Write as _},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::Level;
+
+/// How [`FmtLayer`](crate::FmtLayer) formats every record.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Format {
+    /// `[secs LEVEL](span | span)(module): message field=value`. This is the default.
+    #[default]
+    Text,
+    /// One JSON object per line, with `timestamp`, `elapsed_us`, `level`, `target`,
+    /// `module`, `file`, `line`, `spans`, `message`, `fields`, `trace_id` and `span_id`.
+    Json,
+    /// One JSON object per line following the [Elastic Common Schema], fields other than
+    /// the message are put into `labels`.
+    ///
+    /// [Elastic Common Schema]: https://www.elastic.co/guide/en/ecs/current/index.html
+    Ecs,
+    /// One JSON object per line using the [special fields] of Google Cloud Logging, so
+    /// output printed on GKE or Cloud Run is parsed with its severity and trace.
+    ///
+    /// Traces are only correlated with Cloud Trace if `project_id` is set, otherwise the
+    /// bare trace id is written.
+    ///
+    /// [special fields]: https://cloud.google.com/logging/docs/structured-logging#special-payload-fields
+    Gcp { project_id: Option<String> },
+    /// One JSON object per line following the [OpenTelemetry log data model], with
+    /// `Timestamp`, `SeverityText`, `SeverityNumber`, `Body`, `Attributes`, `Resource`,
+    /// `TraceId` and `SpanId`, for the collector's filelog receiver.
+    ///
+    /// `resource` is written as the `Resource` attributes of every record, for example
+    /// `service.name`.
+    ///
+    /// [OpenTelemetry log data model]: https://opentelemetry.io/docs/specs/otel/logs/data-model/
+    Otel { resource: Vec<(String, String)> },
+    /// One line of `key=value` pairs, `ts`, `level`, `target`, `spans`, `msg`, the fields
+    /// of the event and its spans, `trace_id` and `span_id`. Values containing spaces,
+    /// quotes or `=` are quoted.
+    Logfmt,
+    /// Comma or tab separated values, in the columns chosen with [`Csv`].
+    Csv(Csv),
+}
+
+/// Which times lead the lines of [`Format::Text`], see
+/// [`FmtLayer::with_timestamps`](crate::FmtLayer::with_timestamps).
+///
+/// With all of them, a line starts like
+/// `[2024-05-01T12:00:00.250000Z 1.250000 +0.000800 request+0.012300 INFO]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timestamps {
+    pub(crate) wall_clock: bool,
+    pub(crate) elapsed: bool,
+    pub(crate) delta: bool,
+    pub(crate) delta_scope: DeltaScope,
+    pub(crate) delta_threshold: Option<Duration>,
+    pub(crate) since_span: Option<SpanAnchor>,
+    pub(crate) mock: bool,
+}
+
+/// Which span [`Timestamps::since_span`] is measured from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SpanAnchor {
+    /// The innermost span of the event.
+    Innermost,
+    /// The outermost span of the event, like the one of a whole request.
+    Root,
+}
+
+/// Which previous event [`Timestamps::delta`] is measured from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DeltaScope {
+    /// The previous event of the layer. This is the default.
+    Global,
+    /// The previous event on the same thread.
+    Thread,
+    /// The previous event in the same innermost span, or on the same thread outside of
+    /// spans.
+    Span,
+}
+
+impl Timestamps {
+    /// Only the time since the layer was created.
+    pub fn new() -> Self {
+        Timestamps {
+            wall_clock: false,
+            elapsed: true,
+            delta: false,
+            delta_scope: DeltaScope::Global,
+            delta_threshold: None,
+            since_span: None,
+            mock: false,
+        }
+    }
+
+    /// Show the UTC wall clock time.
+    pub fn wall_clock(mut self, enabled: bool) -> Self {
+        self.wall_clock = enabled;
+        self
+    }
+
+    /// Show the time since the layer was created.
+    pub fn elapsed(mut self, enabled: bool) -> Self {
+        self.elapsed = enabled;
+        self
+    }
+
+    /// Show the time since the previous event, prefixed with `+`.
+    pub fn delta(mut self, enabled: bool) -> Self {
+        self.delta = enabled;
+        self
+    }
+
+    /// Measure the delta from the previous event in `scope`. Events without a previous one
+    /// in their scope show no delta. Implies [`delta`](Self::delta).
+    pub fn delta_scope(mut self, scope: DeltaScope) -> Self {
+        self.delta = true;
+        self.delta_scope = scope;
+        self
+    }
+
+    /// Mark deltas longer than `threshold` with a `!`, like `+2.004100!`, so gaps stand out.
+    /// Implies [`delta`](Self::delta).
+    pub fn highlight_delta(mut self, threshold: Duration) -> Self {
+        self.delta = true;
+        self.delta_threshold = Some(threshold);
+        self
+    }
+
+    /// Show the time since the span at `anchor` was first entered, after its name, like
+    /// `request+0.012300`, to follow the timing within a request. Events outside of spans
+    /// show none.
+    pub fn since_span(mut self, anchor: SpanAnchor) -> Self {
+        self.since_span = Some(anchor);
+        self
+    }
+
+    /// Show every time as zero, like `[1970-01-01T00:00:00.000000Z 0.000000 +0.000000
+    /// request+0.000000 INFO]`, so lines are the same on every run and only differ in what
+    /// matters, see [`FmtLayer::with_snapshot`](crate::FmtLayer::with_snapshot).
+    pub fn mock(mut self, enabled: bool) -> Self {
+        self.mock = enabled;
+        self
+    }
+}
+
+impl Default for Timestamps {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+type WallClock = Box<dyn Fn(SystemTime, &mut String) + Send + Sync>;
+
+/// Level labels and wall clock times of [`Format::Text`] in the language of the user, for
+/// command line tools, see [`FmtLayer::with_localization`](crate::FmtLayer::with_localization).
+///
+/// The other formats are read by machines, and keep their labels and times.
+///
+/// ```
+/// # use chrono::{DateTime, Utc};
+/// # use tracing::Level;
+/// # use wgpu_subscriber::Localization;
+/// let localization = Localization::new()
+///     .level(Level::ERROR, "FEHLER")
+///     .level(Level::WARN, "WARNUNG")
+///     .wall_clock(|time, out| {
+///         let time = DateTime::<Utc>::from(time);
+///         out.push_str(&time.format("%d.%m.%Y %H:%M:%S").to_string());
+///     });
+/// ```
+#[derive(Default)]
+pub struct Localization {
+    /// ERROR first.
+    levels: [Option<Cow<'static, str>>; 5],
+    wall_clock: Option<WallClock>,
+}
+
+impl Localization {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Show `level` as `label`.
+    pub fn level(mut self, level: Level, label: impl Into<Cow<'static, str>>) -> Self {
+        self.levels[level_index(&level)] = Some(label.into());
+        self
+    }
+
+    /// Write the wall clock time with `format` instead of as RFC 3339 in UTC, when it is
+    /// shown, see [`Timestamps::wall_clock`].
+    pub fn wall_clock(
+        mut self,
+        format: impl Fn(SystemTime, &mut String) + Send + Sync + 'static,
+    ) -> Self {
+        self.wall_clock = Some(Box::new(format));
+        self
+    }
+}
+
+impl fmt::Debug for Localization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Localization")
+            .field("levels", &self.levels)
+            .field("wall_clock", &self.wall_clock.is_some())
+            .finish()
+    }
+}
+
+fn level_index(level: &Level) -> usize {
+    match *level {
+        Level::ERROR => 0,
+        Level::WARN => 1,
+        Level::INFO => 2,
+        Level::DEBUG => 3,
+        Level::TRACE => 4,
+    }
+}
+
+/// Order of the fields in every format, see
+/// [`FmtLayer::with_field_order`](crate::FmtLayer::with_field_order).
+///
+/// Either way the order is deterministic. The fixed keys of the JSON formats always come
+/// first, in the order documented on [`Format`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum FieldOrder {
+    /// The order of the fields at the call site, event fields before span fields where
+    /// they are merged. This is the default.
+    #[default]
+    Definition,
+    /// Sorted by name, after fields are renamed. Fields with the same name keep their
+    /// definition order.
+    Alphabetical,
+}
+
+/// Options of the layer that formats look at.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FormatOptions {
+    /// See [`FmtLayer::with_error_reports`](crate::FmtLayer::with_error_reports).
+    pub(crate) error_reports: bool,
+    pub(crate) timestamps: Timestamps,
+    /// Event and span fields are sorted before formatting, this only affects fields of both
+    /// merged into one object.
+    pub(crate) field_order: FieldOrder,
+    /// See [`FmtLayer::with_run_id`](crate::FmtLayer::with_run_id).
+    pub(crate) run_id: bool,
+    /// Only used by [`Format::Text`].
+    pub(crate) localization: Option<Arc<Localization>>,
+}
+
+impl Format {
+    pub(crate) fn write(&self, record: &LogRecord, options: &FormatOptions, out: &mut String) {
+        let order = options.field_order;
+        match self {
+            Format::Text => write_text(record, options, out),
+            Format::Json => write_json(record, out),
+            Format::Ecs => write_ecs(record, order, out),
+            Format::Gcp { project_id } => write_gcp(record, project_id.as_deref(), order, out),
+            Format::Otel { resource } => write_otel(record, resource, order, out),
+            Format::Logfmt => write_logfmt(record, order, out),
+            Format::Csv(csv) => csv.write(record, out),
+        }
+    }
+}
+
+fn rfc3339(record: &LogRecord) -> String {
+    DateTime::<Utc>::from(record.timestamp).to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn write_text(record: &LogRecord, options: &FormatOptions, out: &mut String) {
+    let timestamps = &options.timestamps;
+    out.push('[');
+    let localization = options.localization.as_deref();
+    if timestamps.wall_clock {
+        let wall_clock = localization.and_then(|localization| localization.wall_clock.as_ref());
+        match (timestamps.mock, wall_clock) {
+            (true, _) => out.push_str("1970-01-01T00:00:00.000000Z"),
+            (false, Some(wall_clock)) => wall_clock(record.timestamp, out),
+            (false, None) => out.push_str(&rfc3339(record)),
+        }
+        out.push(' ');
+    }
+    if timestamps.elapsed {
+        let micros = match timestamps.mock {
+            true => 0,
+            false => record.time.as_micros() as u64,
+        };
+        let _ = bare::write_secs(out, micros);
+        out.push(' ');
+    }
+    if let (true, Some(delta)) = (timestamps.delta, record.delta) {
+        let delta = match timestamps.mock {
+            true => Duration::ZERO,
+            false => delta,
+        };
+        out.push('+');
+        let _ = bare::write_secs(out, delta.as_micros() as u64);
+        if timestamps
+            .delta_threshold
+            .is_some_and(|threshold| delta > threshold)
+        {
+            out.push('!');
+        }
+        out.push(' ');
+    }
+    let anchor = match timestamps.since_span {
+        Some(SpanAnchor::Innermost) => record.spans.last(),
+        Some(SpanAnchor::Root) => record.spans.first(),
+        None => None,
+    };
+    if let (Some(span), Some(since)) = (anchor, record.since_span) {
+        let micros = match timestamps.mock {
+            true => 0,
+            false => since.as_micros() as u64,
+        };
+        out.push_str(&span.name);
+        out.push('+');
+        let _ = bare::write_secs(out, micros);
+        out.push(' ');
+    }
+    let level = localization
+        .and_then(|localization| localization.levels[level_index(&record.level)].as_deref())
+        .unwrap_or_else(|| bare::level_str(&record.level));
+    let _ = bare::write_scope(
+        out,
+        level,
+        record.spans.iter().map(|span| span.text()),
+        record.module.as_deref().unwrap_or("no module"),
+    );
+    out.push_str(&record.message);
+    if !options.error_reports {
+        for (name, value) in &record.fields {
+            bare::write_field(out, name, value);
+        }
+        return;
+    }
+
+    for (name, value) in &record.fields {
+        match value {
+            FieldValue::Debug(value) if value.contains('\n') => {
+                let first = value.lines().next().unwrap_or_default();
+                bare::write_field(out, name, format_args!("{}…", first));
+            }
+            value => bare::write_field(out, name, value),
+        }
+    }
+    for (name, value) in &record.fields {
+        match value {
+            FieldValue::Error { message, sources } if !sources.is_empty() => {
+                let _ = write!(out, "\n    {}: {}", name, message);
+                for source in sources {
+                    let _ = write!(out, "\n      caused by: {}", source);
+                }
+            }
+            FieldValue::Debug(value) if value.contains('\n') => {
+                let _ = write!(out, "\n    {}:", name);
+                for line in value.lines() {
+                    out.push('\n');
+                    if !line.is_empty() {
+                        out.push_str("      ");
+                        out.push_str(line);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn write_json(record: &LogRecord, out: &mut String) {
+    let mut object = json::Object::new(out);
+    object.str("timestamp", &rfc3339(record));
+    object.u64("elapsed_us", record.time.as_micros() as u64);
+    object.str("level", bare::level_str(&record.level));
+    object.str("target", &record.target);
+    if let Some(ref module) = record.module {
+        object.str("module", module);
+    }
+    if let Some(ref file) = record.file {
+        object.str("file", file);
+    }
+    if let Some(line) = record.line {
+        object.u64("line", line.into());
+    }
+
+    let spans = object.key("spans");
+    spans.push('[');
+    for (i, span) in record.spans.iter().enumerate() {
+        if i != 0 {
+            spans.push(',');
+        }
+        let mut object = json::Object::new(spans);
+        object.str("name", &span.name);
+        if !span.fields.is_empty() {
+            let mut fields = json::Object::new(object.key("fields"));
+            for (name, value) in &span.fields {
+                fields.value(name, value);
+            }
+            fields.finish();
+        }
+        object.finish();
+    }
+    spans.push(']');
+
+    object.str("message", &record.message);
+
+    let mut fields = json::Object::new(object.key("fields"));
+    for (name, value) in &record.fields {
+        fields.value(name, value);
+    }
+    fields.finish();
+
+    write_ids(&mut object, record, "trace_id", "span_id");
+    object.finish();
+}
+
+/// Event and span fields merged, see [`LogRecord::all_fields`].
+fn merged_fields(record: &LogRecord, order: FieldOrder) -> Vec<(&str, &FieldValue)> {
+    let mut fields: Vec<_> = record.all_fields().collect();
+    if order == FieldOrder::Alphabetical {
+        fields.sort_by_key(|(name, _)| *name);
+    }
+    fields
+}
+
+fn lowercase_level(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "error",
+        Level::WARN => "warn",
+        Level::INFO => "info",
+        Level::DEBUG => "debug",
+        Level::TRACE => "trace",
+    }
+}
+
+fn write_ecs(record: &LogRecord, order: FieldOrder, out: &mut String) {
+    let level = lowercase_level(&record.level);
+    let mut object = json::Object::new(out);
+    object.str("@timestamp", &rfc3339(record));
+    object.str("log.level", level);
+    object.str("message", &record.message);
+    object.str("ecs.version", "1.6.0");
+    object.str("log.logger", &record.target);
+    if let Some(ref file) = record.file {
+        object.str("log.origin.file.name", file);
+    }
+    if let Some(line) = record.line {
+        object.u64("log.origin.file.line", line.into());
+    }
+    object.u64("process.pid", std::process::id().into());
+    write_ids(&mut object, record, "trace.id", "span.id");
+
+    let fields = merged_fields(record, order);
+    if !fields.is_empty() {
+        let mut labels = json::Object::new(object.key("labels"));
+        for (name, value) in fields {
+            // Label names may not contain dots, and values are always strings.
+            labels.str(&name.replace('.', "_"), &value.to_string());
+        }
+        labels.finish();
+    }
+    object.finish();
+}
+
+fn write_gcp(record: &LogRecord, project_id: Option<&str>, order: FieldOrder, out: &mut String) {
+    let severity = match record.level {
+        Level::ERROR => "ERROR",
+        Level::WARN => "WARNING",
+        Level::INFO => "INFO",
+        Level::DEBUG | Level::TRACE => "DEBUG",
+    };
+
+    let mut object = json::Object::new(out);
+    object.str("severity", severity);
+    object.str("time", &rfc3339(record));
+    object.str("message", &record.message);
+    object.str("target", &record.target);
+
+    if record.file.is_some() || record.module.is_some() {
+        let mut location = json::Object::new(object.key("logging.googleapis.com/sourceLocation"));
+        if let Some(ref file) = record.file {
+            location.str("file", file);
+        }
+        if let Some(line) = record.line {
+            // Cloud Logging expects the line as a string.
+            location.str("line", itoa::Buffer::new().format(line));
+        }
+        if let Some(ref module) = record.module {
+            location.str("function", module);
+        }
+        location.finish();
+    }
+
+    if let Some(trace_id) = record.trace_id {
+        let trace = match project_id {
+            Some(project_id) => format!("projects/{}/traces/{:032x}", project_id, trace_id),
+            None => format!("{:032x}", trace_id),
+        };
+        object.str("logging.googleapis.com/trace", &trace);
+    }
+    if let Some(span_id) = record.span_id {
+        object.str(
+            "logging.googleapis.com/spanId",
+            &format!("{:016x}", span_id),
+        );
+    }
+
+    let merged = merged_fields(record, order);
+    if !merged.is_empty() {
+        let mut fields = json::Object::new(object.key("fields"));
+        for (name, value) in merged {
+            fields.value(name, value);
+        }
+        fields.finish();
+    }
+    object.finish();
+}
+
+fn write_otel(
+    record: &LogRecord,
+    resource: &[(String, String)],
+    order: FieldOrder,
+    out: &mut String,
+) {
+    let severity_number = match record.level {
+        Level::TRACE => 1,
+        Level::DEBUG => 5,
+        Level::INFO => 9,
+        Level::WARN => 13,
+        Level::ERROR => 17,
+    };
+    let nanos = record
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64);
+
+    let mut object = json::Object::new(out);
+    object.u64("Timestamp", nanos);
+    object.str("SeverityText", bare::level_str(&record.level));
+    object.u64("SeverityNumber", severity_number);
+    object.str("Body", &record.message);
+
+    let mut attributes = json::Object::new(object.key("Attributes"));
+    attributes.str("log.target", &record.target);
+    if let Some(ref module) = record.module {
+        attributes.str("code.namespace", module);
+    }
+    if let Some(ref file) = record.file {
+        attributes.str("code.filepath", file);
+    }
+    if let Some(line) = record.line {
+        attributes.u64("code.lineno", line.into());
+    }
+    for (name, value) in merged_fields(record, order) {
+        attributes.value(name, value);
+    }
+    attributes.finish();
+
+    let mut attributes = json::Object::new(object.key("Resource"));
+    for (name, value) in resource {
+        attributes.str(name, value);
+    }
+    attributes.finish();
+
+    write_ids(&mut object, record, "TraceId", "SpanId");
+    object.finish();
+}
+
+fn write_logfmt(record: &LogRecord, order: FieldOrder, out: &mut String) {
+    let level = lowercase_level(&record.level);
+    let _ = write!(out, "ts={} level={}", rfc3339(record), level);
+    write_logfmt_pair(out, "target", &record.target);
+    if !record.spans.is_empty() {
+        let mut spans = String::new();
+        for (i, span) in record.spans.iter().enumerate() {
+            if i != 0 {
+                spans.push('>');
+            }
+            spans.push_str(&span.name);
+        }
+        write_logfmt_pair(out, "spans", &spans);
+    }
+    write_logfmt_pair(out, "msg", &record.message);
+
+    let mut value = String::new();
+    for (name, field) in merged_fields(record, order) {
+        value.clear();
+        let _ = write!(value, "{}", field);
+        write_logfmt_pair(out, name, &value);
+    }
+
+    if let Some(trace_id) = record.trace_id {
+        let _ = write!(out, " trace_id={:032x}", trace_id);
+    }
+    if let Some(span_id) = record.span_id {
+        let _ = write!(out, " span_id={:016x}", span_id);
+    }
+}
+
+/// Writes ` key=value`, quoting and escaping the value if needed.
+fn write_logfmt_pair(out: &mut String, key: &str, value: &str) {
+    out.push(' ');
+    out.push_str(key);
+    out.push('=');
+    let quote = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c <= ' ' || c == '=' || c == '"' || c == '\\');
+    if !quote {
+        out.push_str(value);
+        return;
+    }
+
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_ids(object: &mut json::Object<'_>, record: &LogRecord, trace_key: &str, span_key: &str) {
+    if let Some(trace_id) = record.trace_id {
+        object.str(trace_key, &format!("{:032x}", trace_id));
+    }
+    if let Some(span_id) = record.span_id {
+        object.str(span_key, &format!("{:016x}", span_id));
+    }
+}
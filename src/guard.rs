@@ -0,0 +1,266 @@
+use crate::{
+    format::{Format, FormatOptions},
+    heartbeat::Heartbeat,
+    stats::dropped_events,
+    table::ProblemTable,
+    writer::{self, Flush, Sink},
+    FieldValue, LogRecord,
+};
+use std::{
+    borrow::Cow,
+    io::{self, Write as _},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::Level;
+
+/// Number of events a [`FmtLayer`](crate::FmtLayer) saw, per level.
+#[derive(Debug, Default)]
+pub(crate) struct EventCounts([AtomicU64; 5]);
+
+impl EventCounts {
+    fn index(level: &Level) -> usize {
+        match *level {
+            Level::ERROR => 0,
+            Level::WARN => 1,
+            Level::INFO => 2,
+            Level::DEBUG => 3,
+            Level::TRACE => 4,
+        }
+    }
+
+    pub(crate) fn count(&self, level: &Level) {
+        self.0[Self::index(level)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self, level: &Level) -> u64 {
+        self.0[Self::index(level)].load(Ordering::Relaxed)
+    }
+
+    /// The counts of all levels, ERROR first.
+    pub(crate) fn all(&self) -> [u64; 5] {
+        [0, 1, 2, 3, 4].map(|index| self.0[index].load(Ordering::Relaxed))
+    }
+}
+
+/// Flushes the output of a [`FmtLayer`](crate::FmtLayer) when dropped, see
+/// [`FmtLayer::guard`](crate::FmtLayer::guard).
+///
+/// Keep it alive in `main`, so buffered lines are written even though a global
+/// subscriber is never dropped.
+///
+/// Events arriving after it is dropped, like those of threads still running while `main`
+/// returns, are still output, but flushed one line at a time.
+///
+/// The guards of several layers can be [combined](FmtGuard::and) and shut down together,
+/// within a timeout, see [`FmtGuard::shutdown`].
+#[must_use = "output is flushed when the guard is dropped"]
+pub struct FmtGuard {
+    start: Instant,
+    sinks: Vec<Sink>,
+    format: Format,
+    options: FormatOptions,
+    counts: Arc<EventCounts>,
+    summary: bool,
+    /// Takes the records held back by tail sampling that should be output.
+    drain: Option<Drain>,
+    heartbeat: Option<Weak<Heartbeat>>,
+    problem_table: Option<Arc<ProblemTable>>,
+    /// Guards of other layers, shut down after this one.
+    others: Vec<FmtGuard>,
+    /// Set once shut down, so dropping does nothing more.
+    done: bool,
+}
+
+type Drain = Box<dyn Fn() -> Vec<LogRecord> + Send + Sync>;
+
+impl FmtGuard {
+    pub(crate) fn new(
+        start: Instant,
+        sinks: Vec<Sink>,
+        format: Format,
+        options: FormatOptions,
+        counts: Arc<EventCounts>,
+        drain: Option<impl Fn() -> Vec<LogRecord> + Send + Sync + 'static>,
+        heartbeat: Option<Weak<Heartbeat>>,
+    ) -> Self {
+        FmtGuard {
+            start,
+            sinks,
+            format,
+            options,
+            counts,
+            summary: false,
+            drain: drain.map(|drain| Box::new(drain) as Drain),
+            heartbeat,
+            problem_table: None,
+            others: Vec::new(),
+            done: false,
+        }
+    }
+
+    pub(crate) fn with_problem_table(mut self, table: Option<Arc<ProblemTable>>) -> Self {
+        self.problem_table = table;
+        self
+    }
+
+    /// Also shut down the layer of `other`, after the one of this guard and any combined
+    /// before, like a network layer after a console one.
+    pub fn and(mut self, other: FmtGuard) -> Self {
+        self.others.push(other);
+        self
+    }
+
+    /// Shuts down the layers of this guard and those combined with it, returning whether
+    /// that finished within `timeout`.
+    ///
+    /// Every step is done for all layers, in the order they were combined, before the next:
+    /// 1. Stop the heartbeats of [`FmtLayer::with_heartbeat`](crate::FmtLayer::with_heartbeat).
+    /// 2. Output what tail sampling holds back and would keep if the traces ended now.
+    /// 3. Flush all sinks.
+    /// 4. Output the summaries of [`FmtGuard::with_summary`], and from then on flush every
+    ///    line, like dropping the guard does. Print the tables of
+    ///    [`FmtLayer::with_problem_table`](crate::FmtLayer::with_problem_table).
+    ///
+    /// On timeout, for example when a socket blocks, the rest carries on in the background.
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use tracing_subscriber::layer::SubscriberExt as _;
+    /// # use wgpu_subscriber::FmtLayer;
+    /// let console = FmtLayer::new();
+    /// let network = FmtLayer::with_socket("/run/collector.sock");
+    /// let guard = console.guard().and(network.guard());
+    /// tracing::subscriber::set_global_default(
+    ///     tracing_subscriber::Registry::default().with(console).with(network),
+    /// )
+    /// .unwrap();
+    /// // ...
+    /// if !guard.shutdown(Duration::from_secs(2)) {
+    ///     eprintln!("logs may be incomplete");
+    /// }
+    /// ```
+    pub fn shutdown(self, timeout: Duration) -> bool {
+        let (done, finished) = mpsc::channel();
+        let mut guard = self;
+        let spawned = thread::Builder::new()
+            .name("wgpu-subscriber shutdown".into())
+            .spawn(move || {
+                guard.shut_down_all();
+                let _ = done.send(());
+            });
+        match spawned {
+            Ok(_) => finished.recv_timeout(timeout).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn shut_down_all(&mut self) {
+        let mut others = std::mem::take(&mut self.others);
+        let mut guards: Vec<&mut FmtGuard> = Some(&mut *self)
+            .into_iter()
+            .chain(others.iter_mut())
+            .collect();
+
+        for guard in &guards {
+            if let Some(heartbeat) = guard.heartbeat.as_ref().and_then(Weak::upgrade) {
+                heartbeat.stop();
+            }
+        }
+        for guard in &guards {
+            let records = guard
+                .drain
+                .as_ref()
+                .map(|drain| drain())
+                .unwrap_or_default();
+            for record in &records {
+                guard.write(record);
+            }
+        }
+        for guard in &guards {
+            for sink in &guard.sinks {
+                let _ = sink.output.flush();
+            }
+        }
+        for guard in &mut guards {
+            guard.close();
+        }
+        drop(guards);
+        self.others = others;
+    }
+
+    fn write(&self, record: &LogRecord) {
+        let mut line = String::new();
+        writer::write_record(
+            &self.sinks,
+            &self.format,
+            &self.options,
+            Flush::Buffered,
+            record,
+            &mut line,
+            &mut Vec::new(),
+        );
+    }
+
+    /// Outputs the summary and flushes the sinks for the last time.
+    fn close(&mut self) {
+        if self.done {
+            return;
+        }
+        self.done = true;
+        if self.summary {
+            self.write(&self.summary());
+        }
+        for sink in &self.sinks {
+            sink.shut_down();
+        }
+        if let Some(ref table) = self.problem_table {
+            let _ = io::stderr().write_all(table.render().as_bytes());
+        }
+    }
+
+    /// Also output a `process summary` record when dropped, with the uptime, the number
+    /// of events per level, the number of dropped events, and whether the guard is
+    /// dropped by a panic unwinding the thread that owns it. It is an ERROR when
+    /// panicking or when errors were logged, INFO otherwise.
+    pub fn with_summary(mut self) -> Self {
+        self.summary = true;
+        self
+    }
+
+    fn summary(&self) -> LogRecord {
+        let panicking = thread::panicking();
+        let errors = self.counts.get(&Level::ERROR);
+        let level = match panicking || errors != 0 {
+            true => Level::ERROR,
+            false => Level::INFO,
+        };
+        let uptime = self.start.elapsed();
+
+        let mut record = LogRecord::internal(uptime, level, "process summary".to_owned());
+        let fields = [
+            ("uptime_ms", FieldValue::U64(uptime.as_millis() as u64)),
+            ("errors", FieldValue::U64(errors)),
+            ("warnings", FieldValue::U64(self.counts.get(&Level::WARN))),
+            ("info", FieldValue::U64(self.counts.get(&Level::INFO))),
+            ("debug", FieldValue::U64(self.counts.get(&Level::DEBUG))),
+            ("trace", FieldValue::U64(self.counts.get(&Level::TRACE))),
+            ("dropped", FieldValue::U64(dropped_events().total())),
+            ("panicking", FieldValue::Bool(panicking)),
+        ];
+        let fields =
+            IntoIterator::into_iter(fields).map(|(name, value)| (Cow::Borrowed(name), value));
+        record.fields.extend(fields);
+        record
+    }
+}
+
+impl Drop for FmtGuard {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
@@ -0,0 +1,108 @@
+use crate::{
+    format::{Format, FormatOptions},
+    guard::EventCounts,
+    stats::{dropped_events, DroppedEvents},
+    writer::{self, Flush, Sink},
+    FieldValue, LogRecord,
+};
+use parking_lot::Mutex;
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
+use tracing::Level;
+
+type HeldBack = Box<dyn Fn() -> (u64, u64) + Send + Sync>;
+
+/// Outputs a `heartbeat` record from a background thread, see
+/// [`FmtLayer::with_heartbeat`](crate::FmtLayer::with_heartbeat).
+pub(crate) struct Heartbeat {
+    start: Instant,
+    sinks: Vec<Sink>,
+    format: Format,
+    options: FormatOptions,
+    counts: Arc<EventCounts>,
+    /// Traces and events held back by tail sampling.
+    held_back: HeldBack,
+    /// Counts at the previous heartbeat.
+    last: Mutex<([u64; 5], DroppedEvents)>,
+    /// Set by [`FmtGuard::shutdown`](crate::FmtGuard::shutdown).
+    stopped: AtomicBool,
+}
+
+impl Heartbeat {
+    pub(crate) fn new(
+        start: Instant,
+        sinks: Vec<Sink>,
+        format: Format,
+        options: FormatOptions,
+        counts: Arc<EventCounts>,
+        held_back: impl Fn() -> (u64, u64) + Send + Sync + 'static,
+    ) -> Self {
+        let last = (counts.all(), dropped_events());
+        Heartbeat {
+            start,
+            sinks,
+            format,
+            options,
+            counts,
+            held_back: Box::new(held_back),
+            last: Mutex::new(last),
+            stopped: AtomicBool::new(false),
+        }
+    }
+
+    /// Outputs a heartbeat every `interval` until it is dropped.
+    pub(crate) fn spawn(heartbeat: Weak<Heartbeat>, interval: Duration) {
+        crate::fork::spawn_worker(crate::fork::Worker::Heartbeat(heartbeat), Some(interval));
+    }
+
+    /// No more heartbeats from now on.
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn beat(&self) {
+        if self.stopped.load(Ordering::Relaxed) {
+            return;
+        }
+        let counts = self.counts.all();
+        let dropped = dropped_events();
+        let (last_counts, last_dropped) =
+            std::mem::replace(&mut *self.last.lock(), (counts, dropped));
+        let since = |index: usize| counts[index] - last_counts[index];
+        let (traces, events) = (self.held_back)();
+
+        let uptime = self.start.elapsed();
+        let mut record = LogRecord::internal(uptime, Level::INFO, "heartbeat".to_owned());
+        let fields = [
+            ("uptime_ms", FieldValue::U64(uptime.as_millis() as u64)),
+            ("events", FieldValue::U64((0..5).map(since).sum())),
+            ("errors", FieldValue::U64(since(0))),
+            ("warnings", FieldValue::U64(since(1))),
+            (
+                "dropped",
+                FieldValue::U64(dropped.since(&last_dropped).total()),
+            ),
+            ("held_back_traces", FieldValue::U64(traces)),
+            ("held_back_events", FieldValue::U64(events)),
+        ];
+        let fields =
+            IntoIterator::into_iter(fields).map(|(name, value)| (Cow::Borrowed(name), value));
+        record.fields.extend(fields);
+
+        writer::write_record(
+            &self.sinks,
+            &self.format,
+            &self.options,
+            Flush::Line,
+            &record,
+            &mut String::new(),
+            &mut Vec::new(),
+        );
+    }
+}
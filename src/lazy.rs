@@ -0,0 +1,60 @@
+use crate::{
+    writer::{Output, Sink, SinkEvent},
+    FieldValue,
+};
+use parking_lot::Mutex;
+use std::{
+    io,
+    sync::{Arc, OnceLock},
+};
+
+type Open = Box<dyn FnOnce() -> io::Result<Sink> + Send>;
+
+/// The output of a sink opened on its first record, see [`Sink::lazy`].
+pub(crate) struct LazyOutput {
+    open: Mutex<Option<Open>>,
+    /// `None` if opening failed.
+    output: OnceLock<Option<Arc<Output>>>,
+    events: Mutex<Vec<SinkEvent>>,
+}
+
+impl LazyOutput {
+    pub(crate) fn new(open: impl FnOnce() -> io::Result<Sink> + Send + 'static) -> Self {
+        LazyOutput {
+            open: Mutex::new(Some(Box::new(open))),
+            output: OnceLock::new(),
+            events: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The output, opening it if this is the first call.
+    pub(crate) fn get(&self) -> io::Result<&Output> {
+        let output = self.output.get_or_init(|| {
+            let open = self.open.lock().take()?;
+            match open() {
+                Ok(sink) => Some(sink.output),
+                Err(err) => {
+                    let event = SinkEvent::unnamed("sink failed to open")
+                        .with_field("error", FieldValue::Str(err.to_string()));
+                    self.events.lock().push(event);
+                    None
+                }
+            }
+        });
+        output
+            .as_deref()
+            .ok_or_else(|| io::Error::other("sink failed to open"))
+    }
+
+    /// The output if it was opened.
+    pub(crate) fn opened(&self) -> Option<&Output> {
+        self.output.get().and_then(Option::as_deref)
+    }
+
+    pub(crate) fn take_events(&self, out: &mut Vec<SinkEvent>) {
+        out.append(&mut self.events.lock());
+        if let Some(output) = self.opened() {
+            output.take_events(out);
+        }
+    }
+}
@@ -1,19 +1,176 @@
 //! Easy to use tracing subscribers tuned to usage in wgpu.
 //!
 //! [`initialize_default_subscriber`] will set everything up
-//! in a default configuration.
+//! in a default configuration, [`initialize_test_subscriber`] does so for a single test.
+//! [`capture_snapshot`] returns what a closure logs, for snapshot tests.
 //!
 //! Subscribers:
 //! - [`ChromeTracingLayer`]: Output to chrome tracing format
-//! - [`FmtLayer`]: Formatted output to stderr/stdout.
+//! - [`FmtLayer`]: Formatted output to stderr/stdout, a file, or a local socket, as text,
+//!   logfmt, CSV or JSON, see [`Format`]. Several [`Sink`]s can each have their own format
+//!   and level, or write compact MessagePack frames instead, see [`read_message_pack`].
+//!   A [`Query`] narrows down what a sink gets beyond levels.
+//!
+//! Filters:
+//! - [`SamplingLayer`]: Keep only a fraction of all traces.
+//! - [`ScopedVerbosity`]: Temporarily log more on one thread, see [`with_level`].
+//! - [`StormProtection`]: Only output warnings and errors during a log storm.
+//!
+//! Events dropped along the way are counted, see [`dropped_events`], and [`MetricRules`]
+//! turn events into counters and histograms, see [`metrics`]. Output can be read back with
+//! [`replay`]. Processes that fork, like daemons, call [`after_fork`] in the child, and
+//! only fork while no other thread logs.
+//!
+//! Features:
+//! - `encryption`: [`FmtLayer::with_encrypted_file`] seals every line for a recipient public
+//!   key, [`decrypt_log_file`] reads it back.
+//! - `max_level_*` and `release_max_level_*`: Statically disable all levels below the given
+//!   one, like the [`tracing` features of the same name][static]. They are forwarded to
+//!   `tracing`, and also compile out the formatting done by this crate, which matters for
+//!   records coming from `log`.
+//!
+//! - `std` (default): Everything but the [`bare`] module needs this. Without it the crate is
+//!   `no_std`, and [`bare::BareSubscriber`] outputs the same format as [`FmtLayer`] into a
+//!   user provided byte sink.
+//! - `rtt`: [`bare::RttSink`] sends the output of a [`bare::BareSubscriber`] over RTT, best
+//!   combined with [`bare::Encoding::Compact`].
+//! - `tui`: [`FmtLayer::with_tui`] keeps lines in memory for [`TuiViewer`], a terminal UI
+//!   with scrollback, level filtering and search.
+//! - `async-flush`: [`FmtLayer::with_flush_task`] flushes buffered output from a tokio task
+//!   instead of a thread.
+//! - `task-context`: [`TaskContext`] adds fields to every event of a tokio task and the
+//!   tasks it [`spawn`]s.
+//! - `protobuf`: [`Sink::with_protobuf`] writes [`proto::LogRecord`] messages, following
+//!   `proto/log_record.proto`.
+//!
+//! [static]: https://docs.rs/tracing/0.1/tracing/level_filters/index.html#compile-time-filters
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
 pub use chrome::*;
+#[cfg(feature = "std")]
+pub use csv::{Csv, CsvColumn};
+#[cfg(feature = "encryption")]
+pub use encryption::*;
+#[cfg(feature = "async-flush")]
+pub use flush_task::FlushTask;
+#[cfg(feature = "std")]
 pub use fmt_layer::*;
+#[cfg(feature = "std")]
+pub use fork::after_fork;
+#[cfg(feature = "std")]
+pub use format::{DeltaScope, FieldOrder, Format, Localization, SpanAnchor, Timestamps};
+#[cfg(feature = "std")]
+pub use guard::FmtGuard;
+#[cfg(feature = "std")]
+pub use mapping::{FieldMapping, LevelMapping};
+#[cfg(feature = "std")]
+pub use metrics::{metrics, Condition, Histogram, MetricRules, MetricValue};
+#[cfg(feature = "std")]
+pub use msgpack::{read_message_pack, read_message_pack_file};
+#[cfg(feature = "std")]
+pub use query::{Query, QueryError};
+#[cfg(feature = "std")]
+pub use record::{FieldValue, LogRecord, SpanLimits, SpanRecord};
+#[cfg(feature = "std")]
+pub use sampling::{SamplingLayer, TailSampling};
+#[cfg(feature = "std")]
+pub use snapshot::Snapshot;
+#[cfg(feature = "std")]
+pub use stats::{dropped_events, DroppedEvents};
+#[cfg(feature = "std")]
 use std::path::Path;
+#[cfg(feature = "std")]
+pub use storm::StormProtection;
+#[cfg(feature = "std")]
+pub use table::GroupBy;
+#[cfg(feature = "task-context")]
+pub use task_context::{spawn, TaskContext};
+#[cfg(feature = "std")]
+use tracing::level_filters::LevelFilter;
+#[cfg(feature = "std")]
 use tracing_subscriber::{layer::SubscriberExt as _, EnvFilter};
+#[cfg(feature = "tui")]
+pub use tui::TuiViewer;
+#[cfg(feature = "std")]
+pub use util::run_id;
+#[cfg(feature = "std")]
+pub use verbosity::{
+    invalidate_filter_cache, raise_level, with_level, ScopedVerbosity, VerbosityGuard,
+};
+#[cfg(feature = "std")]
+pub use writer::{Flush, Sink};
 
+pub mod bare;
+#[cfg(feature = "std")]
+mod capped_file;
+#[cfg(feature = "std")]
 mod chrome;
+#[cfg(feature = "std")]
+mod csv;
+#[cfg(feature = "encryption")]
+mod encryption;
+#[cfg(feature = "async-flush")]
+mod flush_task;
+#[cfg(feature = "std")]
 mod fmt_layer;
+#[cfg(feature = "std")]
+mod fork;
+#[cfg(feature = "std")]
+mod format;
+#[cfg(feature = "std")]
+mod guard;
+#[cfg(feature = "std")]
+mod heartbeat;
+#[cfg(feature = "std")]
+mod json;
+#[cfg(feature = "std")]
+mod lazy;
+#[cfg(feature = "std")]
+mod mapping;
+#[cfg(feature = "std")]
+mod metrics;
+#[cfg(feature = "std")]
+mod msgpack;
+#[cfg(feature = "protobuf")]
+pub mod proto;
+#[cfg(feature = "std")]
+mod query;
+#[cfg(feature = "std")]
+mod quota;
+#[cfg(feature = "std")]
+mod record;
+#[cfg(feature = "std")]
+pub mod replay;
+#[cfg(feature = "rtt")]
+mod rtt;
+#[cfg(feature = "std")]
+mod sampling;
+#[cfg(feature = "std")]
+mod shared_file;
+#[cfg(feature = "std")]
+mod snapshot;
+#[cfg(all(feature = "std", any(unix, windows)))]
+mod socket;
+#[cfg(all(feature = "std", any(unix, windows)))]
+mod spool;
+#[cfg(feature = "std")]
+mod stats;
+#[cfg(feature = "std")]
+mod storm;
+#[cfg(feature = "std")]
+mod table;
+#[cfg(feature = "task-context")]
+mod task_context;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(feature = "std")]
+mod util;
+#[cfg(feature = "std")]
+mod verbosity;
+#[cfg(feature = "std")]
+mod writer;
 
 /// Set up the "standard" logger.
 ///
@@ -23,13 +180,36 @@ mod fmt_layer;
 /// If this function is called, you should **not** set up a log-based logger like env_logger
 /// or fern. This will result in duplicate messages.
 ///
+/// Levels are filtered with `RUST_LOG`, and can be raised temporarily with [`with_level`].
+///
 /// # Args
 ///
 /// - `chrome_tracing_path` if set to `Some`, will create a trace compatible with chrome://tracing
 ///   at that location.
+///
+/// # Panics
+///
+/// If [`try_initialize_default_subscriber`] fails, for example when called twice.
+#[cfg(feature = "std")]
 pub fn initialize_default_subscriber(chrome_trace_path: Option<&Path>) {
-    let chrome_tracing_layer_opt =
-        chrome_trace_path.map(|path| ChromeTracingLayer::with_file(path).unwrap());
+    if let Err(err) = try_initialize_default_subscriber(chrome_trace_path) {
+        panic!("failed to initialize the default subscriber: {}", err);
+    }
+}
+
+/// Like [`initialize_default_subscriber`], but returns an error instead of panicking.
+///
+/// Calling it again after it succeeded, or after another global subscriber was set, fails
+/// with [`InitError::AlreadyInitialized`] and only creates the chrome trace. See
+/// [`is_installed`].
+#[cfg(feature = "std")]
+pub fn try_initialize_default_subscriber(
+    chrome_trace_path: Option<&Path>,
+) -> Result<(), InitError> {
+    let chrome_tracing_layer_opt = chrome_trace_path
+        .map(ChromeTracingLayer::with_file)
+        .transpose()
+        .map_err(InitError::ChromeTrace)?;
 
     // Tracing currently doesn't support type erasure with layer composition
     if let Some(chrome_tracing_layer) = chrome_tracing_layer_opt {
@@ -37,21 +217,117 @@ pub fn initialize_default_subscriber(chrome_trace_path: Option<&Path>) {
             tracing_subscriber::Registry::default()
                 .with(chrome_tracing_layer)
                 .with(FmtLayer::new())
-                .with(EnvFilter::from_default_env()),
+                .with(ScopedVerbosity::new(EnvFilter::from_default_env())),
         )
-        .unwrap();
     } else {
         tracing::subscriber::set_global_default(
             tracing_subscriber::Registry::default()
                 .with(FmtLayer::new())
-                .with(EnvFilter::from_default_env()),
+                .with(ScopedVerbosity::new(EnvFilter::from_default_env())),
         )
-        .unwrap();
     }
+    .map_err(|_| InitError::AlreadyInitialized)?;
+
+    tracing_log::LogTracer::init().map_err(InitError::Logger)
+}
+
+/// Why [`try_initialize_default_subscriber`] failed.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum InitError {
+    /// The chrome trace couldn't be created. Nothing was set up.
+    ChromeTrace(std::io::Error),
+    /// A global subscriber was already set, by this crate or another one, and is kept.
+    AlreadyInitialized,
+    /// The subscriber was set up, but another `log` logger was already set, so records of
+    /// `log` don't reach it.
+    Logger(tracing_log::log::SetLoggerError),
+}
+
+#[cfg(feature = "std")]
+impl std::fmt::Display for InitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InitError::ChromeTrace(_) => f.write_str("failed to create the chrome trace"),
+            InitError::AlreadyInitialized => f.write_str("a global subscriber is already set"),
+            InitError::Logger(_) => f.write_str("a log logger is already set"),
+        }
+    }
+}
 
-    tracing_log::LogTracer::init().unwrap();
+#[cfg(feature = "std")]
+impl std::error::Error for InitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InitError::ChromeTrace(err) => Some(err),
+            InitError::AlreadyInitialized => None,
+            InitError::Logger(err) => Some(err),
+        }
+    }
 }
 
+/// Whether the subscriber events of the current thread go to has a [`FmtLayer`] or a
+/// [`ChromeTracingLayer`] of this crate, set globally or for the thread.
+#[cfg(feature = "std")]
+pub fn is_installed() -> bool {
+    tracing::dispatcher::get_default(|dispatch| {
+        dispatch.is::<FmtLayer>() || dispatch.is::<ChromeTracingLayer>()
+    })
+}
+
+/// Set up a logger for the current thread until the returned guard is dropped, with output
+/// captured by `cargo test`, see [`FmtLayer::with_test_writer`].
+///
+/// Call it at the start of each test that should log. Levels are filtered with `RUST_LOG`,
+/// everything is enabled if it isn't set. Events of other threads, like those of a
+/// multithreaded async runtime, are not seen.
+///
+/// ```
+/// let _guard = wgpu_subscriber::initialize_test_subscriber();
+/// tracing::info!("only shown if the test fails");
+/// ```
+#[cfg(feature = "std")]
+pub fn initialize_test_subscriber() -> tracing::subscriber::DefaultGuard {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::default().add_directive(LevelFilter::TRACE.into()));
+    tracing::subscriber::set_default(
+        tracing_subscriber::Registry::default()
+            .with(FmtLayer::with_test_writer())
+            .with(ScopedVerbosity::new(filter)),
+    )
+}
+
+/// Run `f` with a logger for the current thread, returning everything it logged as one
+/// string that is the same on every run, see [`FmtLayer::with_snapshot`].
+///
+/// Every level is captured, `RUST_LOG` is ignored.
+///
+/// ```
+/// let output = wgpu_subscriber::capture_snapshot(|| {
+///     tracing::info!(b = 2, a = 1, "loaded");
+/// });
+/// assert!(output.starts_with("[0.000000 INFO]"));
+/// assert!(output.ends_with(": loaded a=1 b=2\n"));
+/// ```
+#[cfg(feature = "std")]
+pub fn capture_snapshot(f: impl FnOnce()) -> String {
+    let (layer, snapshot) = FmtLayer::with_snapshot();
+    tracing::subscriber::with_default(tracing_subscriber::Registry::default().with(layer), f);
+    snapshot.take()
+}
+
+/// Whether `level` survives the `max_level_*` features. Constant folded, so everything
+/// behind a `false` is compiled out.
+#[inline(always)]
+fn statically_enabled(level: &tracing::Level) -> bool {
+    *level <= tracing::level_filters::STATIC_MAX_LEVEL
+}
+
+/// Target of the records this crate outputs on its own.
+#[cfg(feature = "std")]
+const TARGET: &str = "wgpu_subscriber";
+
+#[cfg(feature = "std")]
 thread_local! {
     static CURRENT_THREAD_ID: usize = thread_id::get();
 }
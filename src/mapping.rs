@@ -0,0 +1,158 @@
+use crate::{FieldValue, LogRecord};
+use smallvec::SmallVec;
+use std::{borrow::Cow, collections::HashMap, fmt};
+use tracing::Level;
+
+type ValueMap = Box<dyn Fn(&str) -> String + Send + Sync>;
+
+#[derive(Default)]
+struct FieldRule {
+    rename: Option<Cow<'static, str>>,
+    map_value: Option<ValueMap>,
+}
+
+/// Renames fields and rewrites their values before they are output, see
+/// [`FmtLayer::with_field_mapping`](crate::FmtLayer::with_field_mapping).
+///
+/// Rules match the name a field has at the call site, and apply to the fields of events
+/// and spans alike.
+///
+/// ```
+/// # use wgpu_subscriber::FieldMapping;
+/// let mapping = FieldMapping::new()
+///     .rename("err", "error")
+///     .rename("latency_ms", "duration_ms")
+///     .map_value("user", |user| user.to_lowercase());
+/// ```
+#[derive(Default)]
+pub struct FieldMapping {
+    rules: HashMap<String, FieldRule>,
+}
+
+impl FieldMapping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Output the field `from` as `to`.
+    pub fn rename(mut self, from: impl Into<String>, to: impl Into<Cow<'static, str>>) -> Self {
+        self.rules.entry(from.into()).or_default().rename = Some(to.into());
+        self
+    }
+
+    /// Replace the value of the field `field` by the result of `map`, which gets the value as
+    /// it is displayed in text output.
+    pub fn map_value(
+        mut self,
+        field: impl Into<String>,
+        map: impl Fn(&str) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.rules.entry(field.into()).or_default().map_value = Some(Box::new(map));
+        self
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub(crate) fn apply(&self, fields: &mut SmallVec<[(Cow<'static, str>, FieldValue); 8]>) {
+        for (name, value) in fields {
+            let rule = match self.rules.get(&**name) {
+                Some(rule) => rule,
+                None => continue,
+            };
+            if let Some(ref map_value) = rule.map_value {
+                *value = FieldValue::Str(map_value(&value.to_string()));
+            }
+            if let Some(ref rename) = rule.rename {
+                *name = rename.clone();
+            }
+        }
+    }
+}
+
+impl fmt::Debug for FieldMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FieldMapping")
+            .field("fields", &self.rules.keys())
+            .finish()
+    }
+}
+
+/// Changes the level of events of given targets, for crates whose choice of levels doesn't
+/// fit, see [`FmtLayer::with_level_mapping`](crate::FmtLayer::with_level_mapping).
+///
+/// Targets match like [`Condition::target`](crate::Condition::target), including the
+/// modules inside of them. The first rule matching an event applies.
+///
+/// ```
+/// # use tracing::Level;
+/// # use wgpu_subscriber::LevelMapping;
+/// let mapping = LevelMapping::new()
+///     .remap("hyper", Level::ERROR, Level::WARN)
+///     .remap_message("payments", "card declined", Level::ERROR);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct LevelMapping {
+    rules: Vec<LevelRule>,
+}
+
+#[derive(Debug, Clone)]
+struct LevelRule {
+    target: Cow<'static, str>,
+    from: Option<Level>,
+    message: Option<String>,
+    to: Level,
+}
+
+impl LevelMapping {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Output events of `target` at `from` at `to` instead.
+    pub fn remap(mut self, target: impl Into<Cow<'static, str>>, from: Level, to: Level) -> Self {
+        self.rules.push(LevelRule {
+            target: target.into(),
+            from: Some(from),
+            message: None,
+            to,
+        });
+        self
+    }
+
+    /// Output events of `target` whose message contains `message` at `to`, whatever their
+    /// level.
+    pub fn remap_message(
+        mut self,
+        target: impl Into<Cow<'static, str>>,
+        message: impl Into<String>,
+        to: Level,
+    ) -> Self {
+        self.rules.push(LevelRule {
+            target: target.into(),
+            from: None,
+            message: Some(message.into()),
+            to,
+        });
+        self
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub(crate) fn apply(&self, record: &mut LogRecord) {
+        let rule = self.rules.iter().find(|rule| {
+            rule.from.is_none_or(|from| record.level == from)
+                && rule
+                    .message
+                    .as_ref()
+                    .is_none_or(|message| record.message.contains(&**message))
+                && crate::util::in_module(&record.target, &rule.target)
+        });
+        if let Some(rule) = rule {
+            record.level = rule.to;
+        }
+    }
+}
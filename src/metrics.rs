@@ -0,0 +1,292 @@
+use crate::{FieldValue, LogRecord};
+use parking_lot::{const_mutex, Mutex};
+use std::{
+    borrow::Cow,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tracing::Level;
+
+/// Upper bounds of the buckets of a histogram, fitting durations in milliseconds.
+const BUCKETS: [f64; 13] = [
+    1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0, 10000.0,
+];
+
+static REGISTRY: Mutex<Vec<Arc<Metric>>> = const_mutex(Vec::new());
+
+/// Which events a rule of [`MetricRules`] applies to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    /// Every event.
+    Any,
+    /// Events whose target is the given one or inside of it, so `payment` matches
+    /// `payment::card` but not `payments`.
+    Target(Cow<'static, str>),
+    /// Events at the given level or above.
+    Level(Level),
+    /// Events having a field of the given name, on them or their spans, which displays as
+    /// the given value.
+    Field(Cow<'static, str>, String),
+    All(Vec<Condition>),
+    AnyOf(Vec<Condition>),
+}
+
+impl Condition {
+    pub fn target(target: impl Into<Cow<'static, str>>) -> Self {
+        Condition::Target(target.into())
+    }
+
+    pub fn level(level: Level) -> Self {
+        Condition::Level(level)
+    }
+
+    pub fn field(name: impl Into<Cow<'static, str>>, value: impl Into<String>) -> Self {
+        Condition::Field(name.into(), value.into())
+    }
+
+    /// Events matching both `self` and `other`.
+    pub fn and(self, other: Condition) -> Self {
+        match self {
+            Condition::All(mut conditions) => {
+                conditions.push(other);
+                Condition::All(conditions)
+            }
+            condition => Condition::All(vec![condition, other]),
+        }
+    }
+
+    /// Events matching `self`, `other` or both.
+    pub fn or(self, other: Condition) -> Self {
+        match self {
+            Condition::AnyOf(mut conditions) => {
+                conditions.push(other);
+                Condition::AnyOf(conditions)
+            }
+            condition => Condition::AnyOf(vec![condition, other]),
+        }
+    }
+
+    pub(crate) fn matches(&self, record: &LogRecord) -> bool {
+        match self {
+            Condition::Any => true,
+            Condition::Target(target) => crate::util::in_module(&record.target, target),
+            Condition::Level(level) => record.level <= *level,
+            Condition::Field(name, value) => record
+                .all_fields()
+                .any(|(field, field_value)| field == name && field_value.to_string() == *value),
+            Condition::All(conditions) => conditions.iter().all(|c| c.matches(record)),
+            Condition::AnyOf(conditions) => conditions.iter().any(|c| c.matches(record)),
+        }
+    }
+}
+
+/// Counters and histograms derived from events by a [`FmtLayer`](crate::FmtLayer), see
+/// [`FmtLayer::with_metrics`](crate::FmtLayer::with_metrics). Read them with [`metrics`].
+///
+/// Rules see every event reaching the layer, including those later held back by tail
+/// sampling, with fields named as at the call site. Rules of the same name and kind share
+/// one metric, even across layers.
+///
+/// ```
+/// # use tracing::Level;
+/// # use wgpu_subscriber::{Condition, MetricRules};
+/// let rules = MetricRules::new()
+///     .count(
+///         "payment_warnings",
+///         Condition::target("payment").and(Condition::level(Level::WARN)),
+///     )
+///     .histogram("http_latency", "latency_ms", Condition::Any);
+/// ```
+#[derive(Default)]
+pub struct MetricRules {
+    rules: Vec<Rule>,
+}
+
+struct Rule {
+    condition: Condition,
+    /// The field recorded into a histogram, `None` for counters.
+    field: Option<Cow<'static, str>>,
+    metric: Arc<Metric>,
+}
+
+impl MetricRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count the events matching `condition` as the counter `name`.
+    pub fn count(mut self, name: impl Into<Cow<'static, str>>, condition: Condition) -> Self {
+        self.rules.push(Rule {
+            condition,
+            field: None,
+            metric: register(name.into(), false),
+        });
+        self
+    }
+
+    /// Record the value of the numeric field `field` of the events matching `condition`
+    /// into the histogram `name`. Events without that field, or with a value that is not a
+    /// number, are skipped.
+    ///
+    /// Buckets go from 1 to 10000 in steps of 1, 2 and 5, which suits milliseconds.
+    pub fn histogram(
+        mut self,
+        name: impl Into<Cow<'static, str>>,
+        field: impl Into<Cow<'static, str>>,
+        condition: Condition,
+    ) -> Self {
+        self.rules.push(Rule {
+            condition,
+            field: Some(field.into()),
+            metric: register(name.into(), true),
+        });
+        self
+    }
+
+    pub(crate) fn extend(&mut self, other: MetricRules) {
+        self.rules.extend(other.rules);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub(crate) fn apply(&self, record: &LogRecord) {
+        for rule in &self.rules {
+            if !rule.condition.matches(record) {
+                continue;
+            }
+            match (&rule.metric.state, &rule.field) {
+                (State::Counter(count), _) => {
+                    count.fetch_add(1, Ordering::Relaxed);
+                }
+                (State::Histogram(histogram), Some(field)) => {
+                    let value = record
+                        .all_fields()
+                        .find(|(name, _)| name == field)
+                        .and_then(|(_, value)| number(value));
+                    if let Some(value) = value {
+                        histogram.lock().record(value);
+                    }
+                }
+                (State::Histogram(_), None) => {}
+            }
+        }
+    }
+}
+
+impl fmt::Debug for MetricRules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MetricRules")
+            .field(
+                "metrics",
+                &self
+                    .rules
+                    .iter()
+                    .map(|rule| &rule.metric.name)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+fn number(value: &FieldValue) -> Option<f64> {
+    match value {
+        FieldValue::I64(value) => Some(*value as f64),
+        FieldValue::U64(value) => Some(*value as f64),
+        FieldValue::F64(value) => Some(*value),
+        FieldValue::Str(value) => value.parse().ok(),
+        _ => None,
+    }
+}
+
+struct Metric {
+    name: Cow<'static, str>,
+    state: State,
+}
+
+enum State {
+    Counter(AtomicU64),
+    Histogram(Mutex<Histogram>),
+}
+
+fn register(name: Cow<'static, str>, histogram: bool) -> Arc<Metric> {
+    let mut registry = REGISTRY.lock();
+    let existing = registry.iter().find(|metric| {
+        metric.name == name && matches!(metric.state, State::Histogram(_)) == histogram
+    });
+    if let Some(metric) = existing {
+        return metric.clone();
+    }
+
+    let state = match histogram {
+        true => State::Histogram(Mutex::new(Histogram {
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            buckets: BUCKETS.iter().map(|&bound| (bound, 0)).collect(),
+        })),
+        false => State::Counter(AtomicU64::new(0)),
+    };
+    let metric = Arc::new(Metric { name, state });
+    registry.push(metric.clone());
+    metric
+}
+
+/// Values recorded into a histogram of [`MetricRules::histogram`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub count: u64,
+    pub sum: f64,
+    /// Smallest value, infinite while `count` is 0.
+    pub min: f64,
+    /// Largest value, negative infinite while `count` is 0.
+    pub max: f64,
+    /// Upper bound of each bucket and the number of values up to it, above the one of the
+    /// previous bucket. Values above the last bound are only in `count`.
+    pub buckets: Vec<(f64, u64)>,
+}
+
+impl Histogram {
+    fn record(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if let Some((_, count)) = self.buckets.iter_mut().find(|(bound, _)| value <= *bound) {
+            *count += 1;
+        }
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        (self.count != 0).then(|| self.sum / self.count as f64)
+    }
+}
+
+/// Current value of a metric of [`MetricRules`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetricValue {
+    Counter(u64),
+    Histogram(Histogram),
+}
+
+/// All metrics of [`MetricRules`] created so far, by name.
+pub fn metrics() -> Vec<(Cow<'static, str>, MetricValue)> {
+    let mut metrics: Vec<_> = REGISTRY
+        .lock()
+        .iter()
+        .map(|metric| {
+            let value = match metric.state {
+                State::Counter(ref count) => MetricValue::Counter(count.load(Ordering::Relaxed)),
+                State::Histogram(ref histogram) => MetricValue::Histogram(histogram.lock().clone()),
+            };
+            (metric.name.clone(), value)
+        })
+        .collect();
+    metrics.sort_by(|(a, _), (b, _)| a.cmp(b));
+    metrics
+}
@@ -0,0 +1,443 @@
+//! MessagePack encoding of records, for [`Sink::with_message_pack`](crate::Sink::with_message_pack).
+//!
+//! Every record is a frame, a little endian `u32` length followed by a MessagePack map with
+//! the keys of [`Format::Json`](crate::Format::Json): `timestamp` (microseconds since the
+//! Unix epoch), `elapsed_us`, `level`, `target`, `module`, `file`, `line`, `spans`,
+//! `message`, `fields`, `trace_id` (16 bytes, big endian) and `span_id`.
+
+use crate::{bare, FieldValue, LogRecord, SpanRecord};
+use smallvec::SmallVec;
+use std::{
+    borrow::Cow,
+    convert::{TryFrom as _, TryInto as _},
+    fs::File,
+    io::{self, Read},
+    path::Path,
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+use tracing::Level;
+
+type Fields = SmallVec<[(Cow<'static, str>, FieldValue); 8]>;
+
+/// Nesting deeper than this is rejected when decoding.
+const MAX_DEPTH: usize = 16;
+/// Frames longer than this are rejected when decoding, their length isn't trusted, and
+/// records that would need one aren't written.
+pub(crate) const MAX_FRAME: usize = 16 << 20;
+
+/// Appends `record` to `out`, without the frame length.
+pub(crate) fn encode(record: &LogRecord, out: &mut Vec<u8>) {
+    let optional = [
+        record.module.is_some(),
+        record.file.is_some(),
+        record.line.is_some(),
+        record.trace_id.is_some(),
+        record.span_id.is_some(),
+    ];
+    let len = 7 + optional.iter().filter(|&&present| present).count();
+    map(out, len);
+
+    str(out, "timestamp");
+    let timestamp = record
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    uint(out, timestamp.as_micros() as u64);
+    str(out, "elapsed_us");
+    uint(out, record.time.as_micros() as u64);
+    str(out, "level");
+    str(out, bare::level_str(&record.level));
+    str(out, "target");
+    str(out, &record.target);
+    if let Some(ref module) = record.module {
+        str(out, "module");
+        str(out, module);
+    }
+    if let Some(ref file) = record.file {
+        str(out, "file");
+        str(out, file);
+    }
+    if let Some(line) = record.line {
+        str(out, "line");
+        uint(out, line.into());
+    }
+
+    str(out, "spans");
+    header(out, record.spans.len(), 0x90, 0xdc);
+    for span in &record.spans {
+        map(out, 2);
+        str(out, "name");
+        str(out, &span.name);
+        str(out, "fields");
+        fields(out, &span.fields);
+    }
+
+    str(out, "message");
+    str(out, &record.message);
+    str(out, "fields");
+    fields(out, &record.fields);
+
+    if let Some(trace_id) = record.trace_id {
+        str(out, "trace_id");
+        out.extend_from_slice(&[0xc4, 16]);
+        out.extend_from_slice(&trace_id.to_be_bytes());
+    }
+    if let Some(span_id) = record.span_id {
+        str(out, "span_id");
+        uint(out, span_id);
+    }
+}
+
+fn fields(out: &mut Vec<u8>, fields: &[(Cow<'static, str>, FieldValue)]) {
+    map(out, fields.len());
+    for (name, value) in fields {
+        str(out, name);
+        match value {
+            FieldValue::Str(value) | FieldValue::Debug(value) => str(out, value),
+            FieldValue::I64(value) => int(out, *value),
+            FieldValue::U64(value) => uint(out, *value),
+            FieldValue::F64(value) => {
+                out.push(0xcb);
+                out.extend_from_slice(&value.to_be_bytes());
+            }
+            FieldValue::Bool(value) => out.push(if *value { 0xc3 } else { 0xc2 }),
+            FieldValue::Error { message, sources } => {
+                map(out, 2);
+                str(out, "message");
+                str(out, message);
+                str(out, "sources");
+                header(out, sources.len(), 0x90, 0xdc);
+                for source in sources {
+                    str(out, source);
+                }
+            }
+        }
+    }
+}
+
+fn map(out: &mut Vec<u8>, len: usize) {
+    header(out, len, 0x80, 0xde);
+}
+
+/// Writes the header of an array or map, `fix` is the tag of the 4 bit length form and
+/// `long` the one of the 16 bit length form, followed by the 32 bit one.
+fn header(out: &mut Vec<u8>, len: usize, fix: u8, long: u8) {
+    if len < 16 {
+        out.push(fix | len as u8);
+    } else if len <= usize::from(u16::MAX) {
+        out.push(long);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(long + 1);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn str(out: &mut Vec<u8>, value: &str) {
+    let len = value.len();
+    if len < 32 {
+        out.push(0xa0 | len as u8);
+    } else if len <= usize::from(u8::MAX) {
+        out.extend_from_slice(&[0xd9, len as u8]);
+    } else if len <= usize::from(u16::MAX) {
+        out.push(0xda);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(0xdb);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn uint(out: &mut Vec<u8>, value: u64) {
+    if value < 0x80 {
+        out.push(value as u8);
+    } else if value <= u64::from(u8::MAX) {
+        out.extend_from_slice(&[0xcc, value as u8]);
+    } else if value <= u64::from(u16::MAX) {
+        out.push(0xcd);
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= u64::from(u32::MAX) {
+        out.push(0xce);
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        out.push(0xcf);
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn int(out: &mut Vec<u8>, value: i64) {
+    if value >= 0 {
+        uint(out, value as u64);
+    } else if value >= -32 {
+        out.push(value as u8);
+    } else {
+        out.push(0xd3);
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+enum Value<'a> {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    F64(f64),
+    Str(&'a str),
+    Bin(&'a [u8]),
+    Array(Vec<Value<'a>>),
+    Map(Vec<(Value<'a>, Value<'a>)>),
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < len {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    fn be<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N).map(|bytes| bytes.try_into().unwrap())
+    }
+
+    fn len(&mut self, bytes: usize) -> Option<usize> {
+        Some(match bytes {
+            1 => self.be::<1>()?[0].into(),
+            2 => u16::from_be_bytes(self.be()?).into(),
+            _ => u32::from_be_bytes(self.be()?) as usize,
+        })
+    }
+
+    fn value(&mut self, depth: usize) -> Option<Value<'a>> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let tag = self.be::<1>()?[0];
+        Some(match tag {
+            0x00..=0x7f => Value::Uint(tag.into()),
+            0x80..=0x8f => self.map((tag & 0x0f).into(), depth)?,
+            0x90..=0x9f => self.array((tag & 0x0f).into(), depth)?,
+            0xa0..=0xbf => self.str((tag & 0x1f).into())?,
+            0xc0 => Value::Nil,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            0xc4..=0xc6 => {
+                let len = self.len(1 << (tag - 0xc4))?;
+                Value::Bin(self.take(len)?)
+            }
+            0xca => Value::F64(f32::from_be_bytes(self.be()?).into()),
+            0xcb => Value::F64(f64::from_be_bytes(self.be()?)),
+            0xcc => Value::Uint(self.be::<1>()?[0].into()),
+            0xcd => Value::Uint(u16::from_be_bytes(self.be()?).into()),
+            0xce => Value::Uint(u32::from_be_bytes(self.be()?).into()),
+            0xcf => Value::Uint(u64::from_be_bytes(self.be()?)),
+            0xd0 => Value::Int(i8::from_be_bytes(self.be()?).into()),
+            0xd1 => Value::Int(i16::from_be_bytes(self.be()?).into()),
+            0xd2 => Value::Int(i32::from_be_bytes(self.be()?).into()),
+            0xd3 => Value::Int(i64::from_be_bytes(self.be()?)),
+            0xd9..=0xdb => {
+                let len = self.len(1 << (tag - 0xd9))?;
+                self.str(len)?
+            }
+            0xdc | 0xdd => {
+                let len = self.len(2 << (tag - 0xdc))?;
+                self.array(len, depth)?
+            }
+            0xde | 0xdf => {
+                let len = self.len(2 << (tag - 0xde))?;
+                self.map(len, depth)?
+            }
+            0xe0..=0xff => Value::Int((tag as i8).into()),
+            _ => return None,
+        })
+    }
+
+    fn str(&mut self, len: usize) -> Option<Value<'a>> {
+        std::str::from_utf8(self.take(len)?).ok().map(Value::Str)
+    }
+
+    fn array(&mut self, len: usize, depth: usize) -> Option<Value<'a>> {
+        // Every value takes at least a byte, don't trust `len` beyond that.
+        let mut values = Vec::with_capacity(len.min(self.bytes.len()));
+        for _ in 0..len {
+            values.push(self.value(depth + 1)?);
+        }
+        Some(Value::Array(values))
+    }
+
+    fn map(&mut self, len: usize, depth: usize) -> Option<Value<'a>> {
+        let mut entries = Vec::with_capacity(len.min(self.bytes.len() / 2));
+        for _ in 0..len {
+            entries.push((self.value(depth + 1)?, self.value(depth + 1)?));
+        }
+        Some(Value::Map(entries))
+    }
+}
+
+fn entries<'v, 'a>(value: &'v Value<'a>) -> Option<impl Iterator<Item = (&'a str, &'v Value<'a>)>> {
+    match value {
+        Value::Map(entries) => Some(entries.iter().filter_map(|(key, value)| match key {
+            Value::Str(key) => Some((*key, value)),
+            _ => None,
+        })),
+        _ => None,
+    }
+}
+
+fn decode_fields(value: &Value<'_>) -> Option<Fields> {
+    entries(value)?
+        .map(|(name, value)| {
+            let value = match value {
+                Value::Str(value) => FieldValue::Str((*value).to_owned()),
+                Value::Int(value) => FieldValue::I64(*value),
+                Value::Uint(value) => FieldValue::U64(*value),
+                Value::F64(value) => FieldValue::F64(*value),
+                Value::Bool(value) => FieldValue::Bool(*value),
+                Value::Map(_) => {
+                    let mut message = String::new();
+                    let mut sources = Vec::new();
+                    for (key, value) in entries(value)? {
+                        match (key, value) {
+                            ("message", Value::Str(value)) => message = (*value).to_owned(),
+                            ("sources", Value::Array(values)) => {
+                                sources = values
+                                    .iter()
+                                    .filter_map(|source| match source {
+                                        Value::Str(source) => Some((*source).to_owned()),
+                                        _ => None,
+                                    })
+                                    .collect();
+                            }
+                            _ => {}
+                        }
+                    }
+                    FieldValue::Error { message, sources }
+                }
+                _ => return None,
+            };
+            Some((Cow::Owned(name.to_owned()), value))
+        })
+        .collect()
+}
+
+fn decode_record(value: &Value<'_>) -> Option<LogRecord> {
+    let mut record = LogRecord {
+        time: Duration::ZERO,
+        timestamp: UNIX_EPOCH,
+        delta: None,
+        since_span: None,
+        level: Level::INFO,
+        target: Cow::Borrowed(""),
+        module: None,
+        file: None,
+        line: None,
+        spans: Vec::new(),
+        message: String::new(),
+        fields: SmallVec::new(),
+        trace_id: None,
+        span_id: None,
+    };
+    for (key, value) in entries(value)? {
+        match (key, value) {
+            ("timestamp", Value::Uint(micros)) => {
+                record.timestamp = UNIX_EPOCH + Duration::from_micros(*micros);
+            }
+            ("elapsed_us", Value::Uint(micros)) => record.time = Duration::from_micros(*micros),
+            ("level", Value::Str(level)) => record.level = level.parse().ok()?,
+            ("target", Value::Str(target)) => record.target = Cow::Owned((*target).to_owned()),
+            ("module", Value::Str(module)) => {
+                record.module = Some(Cow::Owned((*module).to_owned()));
+            }
+            ("file", Value::Str(file)) => record.file = Some(Cow::Owned((*file).to_owned())),
+            ("line", Value::Uint(line)) => record.line = u32::try_from(*line).ok(),
+            ("spans", Value::Array(spans)) => {
+                for span in spans {
+                    let mut name = "";
+                    let mut fields = SmallVec::new();
+                    for (key, value) in entries(span)? {
+                        match (key, value) {
+                            ("name", Value::Str(value)) => name = value,
+                            ("fields", value) => fields = decode_fields(value)?,
+                            _ => {}
+                        }
+                    }
+                    let mut span = SpanRecord::new(Cow::Owned(name.to_owned()));
+                    span.fields = fields;
+                    span.render();
+                    record.spans.push(Arc::new(span));
+                }
+            }
+            ("message", Value::Str(message)) => record.message = (*message).to_owned(),
+            ("fields", value) => record.fields = decode_fields(value)?,
+            ("trace_id", Value::Bin(id)) => {
+                record.trace_id = Some(u128::from_be_bytes((*id).try_into().ok()?));
+            }
+            ("span_id", Value::Uint(id)) => record.span_id = Some(*id),
+            _ => {}
+        }
+    }
+    Some(record)
+}
+
+/// Decode a log written by a [`Sink::with_message_pack`](crate::Sink::with_message_pack)
+/// sink.
+///
+/// Returns the records in the order they were written. A truncated final frame is
+/// ignored, a frame that isn't a valid record or is longer than 16 MiB is an error. Fields
+/// recorded with `Debug` come back as [`FieldValue::Str`].
+pub fn read_message_pack(mut reader: impl Read) -> io::Result<Vec<LogRecord>> {
+    let mut records = Vec::new();
+    let mut frame = Vec::new();
+    while let Some(record) = read_frame(&mut reader, &mut frame)? {
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Reads the next record, `None` at the end or at a truncated final frame. `frame` is
+/// reused as buffer.
+pub(crate) fn read_frame(
+    reader: &mut impl Read,
+    frame: &mut Vec<u8>,
+) -> io::Result<Option<LogRecord>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let len = u32::from_le_bytes(len);
+    if len as usize > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "MessagePack frame too long",
+        ));
+    }
+    frame.resize(len as usize, 0);
+    match reader.read_exact(frame) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    Decoder { bytes: frame }
+        .value(0)
+        .as_ref()
+        .and_then(decode_record)
+        .map(Some)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid MessagePack record"))
+}
+
+/// Decode the MessagePack log file at `path`. See [`read_message_pack`].
+pub fn read_message_pack_file(path: impl AsRef<Path>) -> io::Result<Vec<LogRecord>> {
+    read_message_pack(io::BufReader::new(File::open(path)?))
+}
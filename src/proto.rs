@@ -0,0 +1,352 @@
+//! Protobuf messages written by [`Sink::with_protobuf`](crate::Sink::with_protobuf).
+//!
+//! These are the types `prost-build` generates from `proto/log_record.proto`, which other
+//! services can compile to read the stream, written out so building doesn't need `protoc`.
+//! A test decodes them with the tags of the schema to keep both in sync. Decode the stream
+//! with [`prost::Message::decode_length_delimited`].
+
+use crate::FieldValue;
+use std::{borrow::Cow, time::UNIX_EPOCH};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct LogRecord {
+    /// Microseconds since the Unix epoch.
+    #[prost(uint64, tag = "1")]
+    pub timestamp_us: u64,
+    /// Microseconds since the layer was created.
+    #[prost(uint64, tag = "2")]
+    pub elapsed_us: u64,
+    #[prost(enumeration = "Level", tag = "3")]
+    pub level: i32,
+    #[prost(string, tag = "4")]
+    pub target: String,
+    #[prost(string, optional, tag = "5")]
+    pub module: Option<String>,
+    #[prost(string, optional, tag = "6")]
+    pub file: Option<String>,
+    #[prost(uint32, optional, tag = "7")]
+    pub line: Option<u32>,
+    /// Outermost first.
+    #[prost(message, repeated, tag = "8")]
+    pub spans: Vec<Span>,
+    #[prost(string, tag = "9")]
+    pub message: String,
+    #[prost(message, repeated, tag = "10")]
+    pub fields: Vec<Field>,
+    /// 16 bytes, big endian. Empty outside of spans.
+    #[prost(bytes = "vec", tag = "11")]
+    pub trace_id: Vec<u8>,
+    #[prost(fixed64, optional, tag = "12")]
+    pub span_id: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Level {
+    Unspecified = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Span {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(message, repeated, tag = "2")]
+    pub fields: Vec<Field>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Field {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(oneof = "field::Value", tags = "2, 3, 4, 5, 6, 7, 8")]
+    pub value: Option<field::Value>,
+}
+
+pub mod field {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Value {
+        #[prost(string, tag = "2")]
+        Str(String),
+        #[prost(sint64, tag = "3")]
+        I64(i64),
+        #[prost(uint64, tag = "4")]
+        U64(u64),
+        #[prost(double, tag = "5")]
+        F64(f64),
+        #[prost(bool, tag = "6")]
+        Bool(bool),
+        #[prost(message, tag = "7")]
+        Error(super::Error),
+        /// The `Debug` output of anything else.
+        #[prost(string, tag = "8")]
+        Debug(String),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Error {
+    #[prost(string, tag = "1")]
+    pub message: String,
+    /// Outermost first.
+    #[prost(string, repeated, tag = "2")]
+    pub sources: Vec<String>,
+}
+
+fn fields(fields: &[(Cow<'static, str>, FieldValue)]) -> Vec<Field> {
+    fields
+        .iter()
+        .map(|(name, value)| Field {
+            name: name.to_string(),
+            value: Some(match value {
+                FieldValue::Str(value) => field::Value::Str(value.clone()),
+                FieldValue::I64(value) => field::Value::I64(*value),
+                FieldValue::U64(value) => field::Value::U64(*value),
+                FieldValue::F64(value) => field::Value::F64(*value),
+                FieldValue::Bool(value) => field::Value::Bool(*value),
+                FieldValue::Error { message, sources } => field::Value::Error(Error {
+                    message: message.clone(),
+                    sources: sources.clone(),
+                }),
+                FieldValue::Debug(value) => field::Value::Debug(value.clone()),
+            }),
+        })
+        .collect()
+}
+
+impl From<&crate::LogRecord> for LogRecord {
+    fn from(record: &crate::LogRecord) -> Self {
+        let level = match record.level {
+            tracing::Level::ERROR => Level::Error,
+            tracing::Level::WARN => Level::Warn,
+            tracing::Level::INFO => Level::Info,
+            tracing::Level::DEBUG => Level::Debug,
+            tracing::Level::TRACE => Level::Trace,
+        };
+        let timestamp = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        LogRecord {
+            timestamp_us: timestamp.as_micros() as u64,
+            elapsed_us: record.time.as_micros() as u64,
+            level: level.into(),
+            target: record.target.to_string(),
+            module: record.module.as_deref().map(str::to_owned),
+            file: record.file.as_deref().map(str::to_owned),
+            line: record.line,
+            spans: record
+                .spans
+                .iter()
+                .map(|span| Span {
+                    name: span.name.to_string(),
+                    fields: fields(&span.fields),
+                })
+                .collect(),
+            message: record.message.clone(),
+            fields: fields(&record.fields),
+            trace_id: record
+                .trace_id
+                .map(|id| id.to_be_bytes().to_vec())
+                .unwrap_or_default(),
+            span_id: record.span_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FieldValue, SpanRecord};
+    use prost::Message as _;
+    use std::{
+        borrow::Cow, collections::HashMap, convert::TryInto as _, sync::Arc, time::Duration,
+    };
+
+    const SCHEMA: &str = include_str!("../proto/log_record.proto");
+
+    /// Name and type of every field in the schema, by message and tag.
+    fn schema() -> HashMap<(&'static str, u64), (&'static str, &'static str)> {
+        let mut fields = HashMap::new();
+        let mut message = None;
+        for line in SCHEMA.lines().map(str::trim) {
+            let words: Vec<_> = line.split_whitespace().collect();
+            match words[..] {
+                ["message", name, "{"] => message = Some(name),
+                ["enum", _, "{"] => message = None,
+                [.., ty, name, "=", tag] => {
+                    if let Some(message) = message {
+                        let tag = tag.trim_end_matches(';').parse().unwrap();
+                        fields.insert((message, tag), (name, ty));
+                    }
+                }
+                _ => {}
+            }
+        }
+        fields
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Varint(u64),
+        Fixed64(u64),
+        Bytes(Vec<u8>),
+        Message(Vec<(&'static str, Value)>),
+    }
+
+    fn varint(bytes: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = bytes[0];
+            *bytes = &bytes[1..];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    /// Decodes `bytes` as `message` of the schema, failing on tags it doesn't have or wire
+    /// types that don't match their type.
+    fn decode(
+        schema: &HashMap<(&'static str, u64), (&'static str, &'static str)>,
+        message: &str,
+        mut bytes: &[u8],
+    ) -> Vec<(&'static str, Value)> {
+        let mut fields = Vec::new();
+        while !bytes.is_empty() {
+            let key = varint(&mut bytes);
+            let (name, ty) = *schema
+                .get(&(message, key >> 3))
+                .unwrap_or_else(|| panic!("{} has no tag {}", message, key >> 3));
+            let value = match (key & 7, ty) {
+                (0, "uint64" | "uint32" | "sint64" | "bool" | "Level") => {
+                    Value::Varint(varint(&mut bytes))
+                }
+                (1, "fixed64" | "double") => {
+                    let (value, rest) = bytes.split_at(8);
+                    bytes = rest;
+                    Value::Fixed64(u64::from_le_bytes(value.try_into().unwrap()))
+                }
+                (2, _) => {
+                    let len = varint(&mut bytes) as usize;
+                    let (value, rest) = bytes.split_at(len);
+                    bytes = rest;
+                    match ty {
+                        "string" | "bytes" => Value::Bytes(value.to_vec()),
+                        _ => Value::Message(decode(schema, ty, value)),
+                    }
+                }
+                (wire, _) => panic!("{}.{} of type {} has wire type {}", message, name, ty, wire),
+            };
+            fields.push((name, value));
+        }
+        fields
+    }
+
+    fn get<'a>(fields: &'a [(&'static str, Value)], name: &str) -> &'a Value {
+        &fields.iter().find(|(field, _)| *field == name).unwrap().1
+    }
+
+    fn bytes(text: &str) -> Value {
+        Value::Bytes(text.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn matches_schema() {
+        let values = vec![
+            (Cow::Borrowed("str"), FieldValue::Str("text".to_owned())),
+            (Cow::Borrowed("i64"), FieldValue::I64(-3)),
+            (Cow::Borrowed("u64"), FieldValue::U64(7)),
+            (Cow::Borrowed("f64"), FieldValue::F64(0.5)),
+            (Cow::Borrowed("bool"), FieldValue::Bool(true)),
+            (
+                Cow::Borrowed("error"),
+                FieldValue::Error {
+                    message: "failed".to_owned(),
+                    sources: vec!["cause".to_owned()],
+                },
+            ),
+            (
+                Cow::Borrowed("debug"),
+                FieldValue::Debug("Some(1)".to_owned()),
+            ),
+        ];
+        let mut span = SpanRecord::new(Cow::Borrowed("request"));
+        span.fields.push(values[0].clone());
+        let mut record = crate::LogRecord::internal(
+            Duration::from_micros(42),
+            tracing::Level::WARN,
+            "hi".into(),
+        );
+        record.file = Some(Cow::Borrowed("main.rs"));
+        record.line = Some(9);
+        record.spans.push(Arc::new(span));
+        record.fields.extend(values);
+        record.trace_id = Some(1);
+        record.span_id = Some(2);
+
+        let schema = schema();
+        let encoded = super::LogRecord::from(&record).encode_to_vec();
+        let fields = decode(&schema, "LogRecord", &encoded);
+
+        let mut names: Vec<_> = fields.iter().map(|(name, _)| *name).collect();
+        names.dedup();
+        let mut expected: Vec<_> = schema
+            .iter()
+            .filter(|((message, _), _)| *message == "LogRecord")
+            .map(|(&(_, tag), &(name, _))| (tag, name))
+            .collect();
+        expected.sort();
+        let expected: Vec<_> = expected.into_iter().map(|(_, name)| name).collect();
+        assert_eq!(names, expected);
+
+        assert_eq!(*get(&fields, "elapsed_us"), Value::Varint(42));
+        assert_eq!(*get(&fields, "level"), Value::Varint(2));
+        assert_eq!(*get(&fields, "target"), bytes(crate::TARGET));
+        assert_eq!(*get(&fields, "file"), bytes("main.rs"));
+        assert_eq!(*get(&fields, "line"), Value::Varint(9));
+        assert_eq!(*get(&fields, "message"), bytes("hi"));
+        let mut trace_id = vec![0; 15];
+        trace_id.push(1);
+        assert_eq!(*get(&fields, "trace_id"), Value::Bytes(trace_id));
+        assert_eq!(*get(&fields, "span_id"), Value::Fixed64(2));
+
+        let span = match get(&fields, "spans") {
+            Value::Message(span) => span,
+            other => panic!("span is {:?}", other),
+        };
+        assert_eq!(*get(span, "name"), bytes("request"));
+
+        let values: Vec<_> = fields
+            .iter()
+            .filter(|(name, _)| *name == "fields")
+            .map(|(_, field)| match field {
+                Value::Message(field) => &field[1],
+                other => panic!("field is {:?}", other),
+            })
+            .collect();
+        let error = Value::Message(vec![
+            ("message", bytes("failed")),
+            ("sources", bytes("cause")),
+        ]);
+        let expected = [
+            ("str", bytes("text")),
+            ("i64", Value::Varint(5)),
+            ("u64", Value::Varint(7)),
+            ("f64", Value::Fixed64(0.5f64.to_bits())),
+            ("bool", Value::Varint(1)),
+            ("error", error),
+            ("debug", bytes("Some(1)")),
+        ];
+        assert_eq!(values.len(), expected.len());
+        for ((name, value), expected) in values.into_iter().zip(&expected) {
+            assert_eq!((*name, value), (expected.0, &expected.1));
+        }
+    }
+}
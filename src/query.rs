@@ -0,0 +1,580 @@
+use crate::{FieldValue, LogRecord};
+use std::{cmp::Ordering, fmt, str::FromStr};
+use tracing::Level;
+
+/// A filter expression over the level, target, message and fields of a record, see
+/// [`Sink::with_query`](crate::Sink::with_query).
+///
+/// ```
+/// # use wgpu_subscriber::Query;
+/// let query: Query = r#"level >= warn && target starts_with "payments" && fields.status >= 500"#
+///     .parse()?;
+/// # Ok::<(), wgpu_subscriber::QueryError>(())
+/// ```
+///
+/// Comparisons have the form `operand operator value`:
+/// - `level` compares by severity with `trace`, `debug`, `info`, `warn` or `error`, so
+///   `level >= warn` keeps warnings and errors.
+/// - `target`, `message` and `fields.<name>` compare with a quoted string, a number, `true`
+///   or `false`. Fields are looked up on the event, then on its spans, innermost first.
+///   Numbers compare as numbers, everything else by its text.
+///
+/// Operators are `==`, `!=`, `<`, `<=`, `>`, `>=`, `starts_with` and `contains`, the last
+/// two taking a string. `fields.<name>` on its own checks that the field is there.
+/// Comparisons combine with `&&`, `||`, `!` and parentheses, `&&` binding tighter than
+/// `||`. A comparison with a field the record doesn't have, or with a value of another
+/// type, is false.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    All(Vec<Expr>),
+    AnyOf(Vec<Expr>),
+    Not(Box<Expr>),
+    Exists(String),
+    Compare(Operand, Op, Literal),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Level,
+    Target,
+    Message,
+    Field(String),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    StartsWith,
+    Contains,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    Level(Level),
+    Str(String),
+    Int(i128),
+    Float(f64),
+    Bool(bool),
+}
+
+impl Query {
+    /// Parses `text`, see [`Query`] for the syntax.
+    pub fn parse(text: &str) -> Result<Self, QueryError> {
+        let mut parser = Parser {
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+        let expr = parser.any_of(0)?;
+        parser.whitespace();
+        if parser.pos != parser.bytes.len() {
+            return Err(parser.error("expected `&&`, `||` or the end"));
+        }
+        Ok(Query { expr })
+    }
+
+    pub(crate) fn matches(&self, record: &LogRecord) -> bool {
+        self.expr.matches(record)
+    }
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        Query::parse(text)
+    }
+}
+
+impl Expr {
+    fn matches(&self, record: &LogRecord) -> bool {
+        match self {
+            Expr::All(exprs) => exprs.iter().all(|expr| expr.matches(record)),
+            Expr::AnyOf(exprs) => exprs.iter().any(|expr| expr.matches(record)),
+            Expr::Not(expr) => !expr.matches(record),
+            Expr::Exists(name) => record.all_fields().any(|(field, _)| field == name),
+            Expr::Compare(Operand::Level, op, Literal::Level(level)) => {
+                op.holds(severity(&record.level).cmp(&severity(level)))
+            }
+            Expr::Compare(Operand::Level, ..) => false,
+            Expr::Compare(Operand::Target, op, literal) => {
+                compare_str(&record.target, *op, literal)
+            }
+            Expr::Compare(Operand::Message, op, literal) => {
+                compare_str(&record.message, *op, literal)
+            }
+            Expr::Compare(Operand::Field(name), op, literal) => record
+                .all_fields()
+                .find(|(field, _)| field == name)
+                .is_some_and(|(_, value)| compare_field(value, *op, literal)),
+        }
+    }
+}
+
+impl Op {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Op::Eq => ordering == Ordering::Equal,
+            Op::Ne => ordering != Ordering::Equal,
+            Op::Lt => ordering == Ordering::Less,
+            Op::Le => ordering != Ordering::Greater,
+            Op::Gt => ordering == Ordering::Greater,
+            Op::Ge => ordering != Ordering::Less,
+            Op::StartsWith | Op::Contains => false,
+        }
+    }
+}
+
+/// `tracing` orders levels by verbosity, queries by severity.
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::TRACE => 0,
+        Level::DEBUG => 1,
+        Level::INFO => 2,
+        Level::WARN => 3,
+        Level::ERROR => 4,
+    }
+}
+
+fn compare_str(text: &str, op: Op, literal: &Literal) -> bool {
+    match (op, literal) {
+        (Op::StartsWith, Literal::Str(prefix)) => text.starts_with(&**prefix),
+        (Op::Contains, Literal::Str(part)) => text.contains(&**part),
+        (_, Literal::Str(other)) => op.holds(text.cmp(other)),
+        (_, Literal::Int(_) | Literal::Float(_)) => match text.trim().parse::<f64>() {
+            Ok(number) => compare_float(number, op, literal),
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+fn compare_float(number: f64, op: Op, literal: &Literal) -> bool {
+    let other = match *literal {
+        Literal::Int(other) => other as f64,
+        Literal::Float(other) => other,
+        _ => return false,
+    };
+    number
+        .partial_cmp(&other)
+        .is_some_and(|ordering| op.holds(ordering))
+}
+
+fn compare_field(value: &FieldValue, op: Op, literal: &Literal) -> bool {
+    match (value, literal) {
+        (FieldValue::I64(value), Literal::Int(other)) => op.holds(i128::from(*value).cmp(other)),
+        (FieldValue::U64(value), Literal::Int(other)) => op.holds(i128::from(*value).cmp(other)),
+        (FieldValue::I64(value), Literal::Float(_)) => compare_float(*value as f64, op, literal),
+        (FieldValue::U64(value), Literal::Float(_)) => compare_float(*value as f64, op, literal),
+        (FieldValue::F64(value), _) => compare_float(*value, op, literal),
+        (FieldValue::Bool(value), Literal::Bool(other)) => op.holds(value.cmp(other)),
+        (
+            FieldValue::Str(text)
+            | FieldValue::Debug(text)
+            | FieldValue::Error { message: text, .. },
+            _,
+        ) => compare_str(text, op, literal),
+        _ => false,
+    }
+}
+
+/// Why a [`Query`] couldn't be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError {
+    position: usize,
+    message: &'static str,
+}
+
+impl QueryError {
+    /// Offset in bytes into the query where parsing stopped.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {} of the query", self.message, self.position)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// Nesting deeper than this is rejected.
+const MAX_DEPTH: usize = 32;
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &'static str) -> QueryError {
+        QueryError {
+            position: self.pos,
+            message,
+        }
+    }
+
+    fn whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.bytes.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.whitespace();
+        let end = self.pos + token.len();
+        let found = self.bytes.get(self.pos..end) == Some(token.as_bytes());
+        if found {
+            self.pos = end;
+        }
+        found
+    }
+
+    fn any_of(&mut self, depth: usize) -> Result<Expr, QueryError> {
+        let mut exprs = vec![self.all(depth)?];
+        while self.eat("||") {
+            exprs.push(self.all(depth)?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.pop().unwrap(),
+            _ => Expr::AnyOf(exprs),
+        })
+    }
+
+    fn all(&mut self, depth: usize) -> Result<Expr, QueryError> {
+        let mut exprs = vec![self.unary(depth)?];
+        while self.eat("&&") {
+            exprs.push(self.unary(depth)?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.pop().unwrap(),
+            _ => Expr::All(exprs),
+        })
+    }
+
+    fn unary(&mut self, depth: usize) -> Result<Expr, QueryError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+        // Not `!=`, which can't start a comparison anyway.
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary(depth + 1)?)));
+        }
+        if self.eat("(") {
+            let expr = self.any_of(depth + 1)?;
+            if !self.eat(")") {
+                return Err(self.error("expected `)`"));
+            }
+            return Ok(expr);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, QueryError> {
+        self.whitespace();
+        let start = self.pos;
+        let operand = match self.word() {
+            "level" => Operand::Level,
+            "target" => Operand::Target,
+            "message" => Operand::Message,
+            word => match word.strip_prefix("fields.") {
+                Some(name) if !name.is_empty() => Operand::Field(name.to_owned()),
+                _ => {
+                    self.pos = start;
+                    return Err(self.error("expected `level`, `target`, `message` or `fields.`"));
+                }
+            },
+        };
+
+        let op = match self.op() {
+            Some(op) => op,
+            None => {
+                return match operand {
+                    Operand::Field(name) => Ok(Expr::Exists(name)),
+                    _ => Err(self.error("expected an operator")),
+                }
+            }
+        };
+
+        self.whitespace();
+        let start = self.pos;
+        let literal = self.literal(&operand)?;
+        let fits = match (&operand, op, &literal) {
+            (Operand::Level, Op::StartsWith | Op::Contains, _) => false,
+            (_, Op::StartsWith | Op::Contains, literal) => matches!(literal, Literal::Str(_)),
+            _ => true,
+        };
+        if !fits {
+            self.pos = start;
+            return Err(self.error("operator can't be used with this value"));
+        }
+        Ok(Expr::Compare(operand, op, literal))
+    }
+
+    fn op(&mut self) -> Option<Op> {
+        const OPS: [(&str, Op); 8] = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+            ("starts_with", Op::StartsWith),
+            ("contains", Op::Contains),
+        ];
+        OPS.iter()
+            .find(
+                |(token, _)| match token.as_bytes()[0].is_ascii_alphabetic() {
+                    true => self.eat_word(token),
+                    false => self.eat(token),
+                },
+            )
+            .map(|(_, op)| *op)
+    }
+
+    /// Like [`Parser::eat`], but for a word that must not be followed by more of one.
+    fn eat_word(&mut self, token: &str) -> bool {
+        let start = self.pos;
+        if !self.eat(token) {
+            return false;
+        }
+        if let Some(b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.') =
+            self.bytes.get(self.pos)
+        {
+            self.pos = start;
+            return false;
+        }
+        true
+    }
+
+    /// Letters, digits, `_` and `.`, possibly none.
+    fn word(&mut self) -> &'a str {
+        let start = self.pos;
+        while let Some(b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.') =
+            self.bytes.get(self.pos)
+        {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos]).unwrap()
+    }
+
+    fn literal(&mut self, operand: &Operand) -> Result<Literal, QueryError> {
+        let start = self.pos;
+        if self.bytes.get(self.pos) == Some(&b'"') {
+            let text = self.string()?;
+            if *operand != Operand::Level {
+                return Ok(Literal::Str(text));
+            }
+            return level(&text).ok_or_else(|| self.error_at(start, "expected a level"));
+        }
+        if let Some(b'-' | b'0'..=b'9') = self.bytes.get(self.pos) {
+            self.pos += 1;
+            // Also signed exponents, like `5e-3`.
+            while let Some(b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.' | b'+' | b'-') =
+                self.bytes.get(self.pos)
+            {
+                let sign = matches!(self.bytes[self.pos], b'+' | b'-');
+                if sign && !matches!(self.bytes[self.pos - 1], b'e' | b'E') {
+                    break;
+                }
+                self.pos += 1;
+            }
+            let text = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap();
+            let number = match text.contains(['.', 'e', 'E']) {
+                true => text.parse().ok().map(Literal::Float),
+                false => text.parse().ok().map(Literal::Int),
+            };
+            return match (operand, number) {
+                (Operand::Level, _) => Err(self.error_at(start, "expected a level")),
+                (_, Some(number)) => Ok(number),
+                (_, None) => Err(self.error_at(start, "invalid number")),
+            };
+        }
+        let word = self.word();
+        match (operand, word) {
+            (Operand::Level, word) => level(word),
+            (_, "true") => Some(Literal::Bool(true)),
+            (_, "false") => Some(Literal::Bool(false)),
+            _ => None,
+        }
+        .ok_or_else(|| match operand {
+            Operand::Level => self.error_at(start, "expected a level"),
+            _ => self.error_at(start, "expected a string, number, `true` or `false`"),
+        })
+    }
+
+    fn error_at(&self, position: usize, message: &'static str) -> QueryError {
+        QueryError { position, message }
+    }
+
+    /// A string in double quotes, in which `\"` and `\\` stand for `"` and `\`.
+    fn string(&mut self) -> Result<String, QueryError> {
+        let start = self.pos;
+        self.pos += 1;
+        let mut out = Vec::new();
+        loop {
+            let byte = match self.bytes.get(self.pos) {
+                Some(byte) => *byte,
+                None => return Err(self.error_at(start, "unterminated string")),
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => return Ok(String::from_utf8(out).unwrap()),
+                b'\\' => match self.bytes.get(self.pos) {
+                    Some(escaped @ (b'"' | b'\\')) => {
+                        out.push(*escaped);
+                        self.pos += 1;
+                    }
+                    _ => return Err(self.error("expected `\"` or `\\` after `\\`")),
+                },
+                byte => out.push(byte),
+            }
+        }
+    }
+}
+
+fn level(name: &str) -> Option<Literal> {
+    let level = match &*name.to_ascii_lowercase() {
+        "trace" => Level::TRACE,
+        "debug" => Level::DEBUG,
+        "info" => Level::INFO,
+        "warn" => Level::WARN,
+        "error" => Level::ERROR,
+        _ => return None,
+    };
+    Some(Literal::Level(level))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{borrow::Cow, sync::Arc, time::Duration};
+
+    fn record(level: Level, target: &'static str, message: &str) -> LogRecord {
+        let mut record = LogRecord::internal(Duration::ZERO, level, message.to_owned());
+        record.target = Cow::Borrowed(target);
+        record
+    }
+
+    fn matches(query: &str, record: &LogRecord) -> bool {
+        Query::parse(query).unwrap().matches(record)
+    }
+
+    fn error(query: &str) -> (usize, String) {
+        let error = Query::parse(query).unwrap_err();
+        (error.position(), error.message.to_owned())
+    }
+
+    #[test]
+    fn levels_compare_by_severity() {
+        let warn = record(Level::WARN, "app", "");
+        assert!(matches("level >= warn", &warn));
+        assert!(matches("level > info", &warn));
+        assert!(!matches("level >= error", &warn));
+        assert!(matches("level == \"WARN\"", &warn));
+        assert!(!matches("level >= warn", &record(Level::DEBUG, "app", "")));
+    }
+
+    #[test]
+    fn strings() {
+        let record = record(Level::INFO, "payments::card", "charged \"card\"");
+        assert!(matches(r#"target starts_with "payments""#, &record));
+        assert!(!matches(r#"target starts_with "card""#, &record));
+        assert!(matches(r#"message contains "\"card\"""#, &record));
+        assert!(matches(r#"target != "payments""#, &record));
+        assert!(matches(r#"target > "pay""#, &record));
+    }
+
+    #[test]
+    fn fields() {
+        let mut record = record(Level::ERROR, "app", "");
+        record.fields.extend([
+            (Cow::Borrowed("status"), FieldValue::U64(503)),
+            (Cow::Borrowed("ratio"), FieldValue::F64(0.004)),
+            (Cow::Borrowed("retry"), FieldValue::Bool(false)),
+            (Cow::Borrowed("code"), FieldValue::Str("42".to_owned())),
+        ]);
+        let mut span = crate::SpanRecord::new(Cow::Borrowed("request"));
+        span.fields
+            .push((Cow::Borrowed("user"), FieldValue::Str("ann".to_owned())));
+        record.spans.push(Arc::new(span));
+
+        assert!(matches("fields.status >= 500", &record));
+        assert!(matches("fields.status < 503.5", &record));
+        assert!(matches("fields.ratio < 5e-3", &record));
+        assert!(matches("fields.ratio > 4E-4", &record));
+        assert!(matches("fields.ratio > -1", &record));
+        assert!(matches("fields.retry == false", &record));
+        assert!(matches("fields.code == 42", &record));
+        assert!(matches(r#"fields.user == "ann""#, &record));
+        assert!(matches("fields.user", &record));
+        assert!(!matches("fields.missing", &record));
+        assert!(!matches("fields.missing != 1", &record));
+        assert!(!matches(r#"fields.status == "503x""#, &record));
+    }
+
+    #[test]
+    fn combinators() {
+        let record = record(Level::WARN, "hyper", "");
+        assert!(matches(r#"level == error || target == "hyper""#, &record));
+        assert!(!matches(r#"level == error && target == "hyper""#, &record));
+        assert!(matches(
+            r#"level == error && target == "x" || level == warn"#,
+            &record
+        ));
+        assert!(!matches(
+            r#"level == error && (target == "x" || level == warn)"#,
+            &record
+        ));
+        assert!(matches(
+            r#"!(level == error) && !target starts_with "x""#,
+            &record
+        ));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error(""),
+            (
+                0,
+                "expected `level`, `target`, `message` or `fields.`".into()
+            )
+        );
+        assert_eq!(error("level >= loud"), (9, "expected a level".into()));
+        assert_eq!(
+            error("level contains warn"),
+            (15, "operator can't be used with this value".into())
+        );
+        assert_eq!(
+            error("fields.x contains 1"),
+            (18, "operator can't be used with this value".into())
+        );
+        assert_eq!(
+            error(r#"fields.x containsfoo "a""#),
+            (9, "expected `&&`, `||` or the end".into())
+        );
+        assert_eq!(error("fields.d >= 5e-"), (12, "invalid number".into()));
+        assert_eq!(
+            error("fields.d >= 1-2"),
+            (13, "expected `&&`, `||` or the end".into())
+        );
+        assert_eq!(
+            error(r#"message == "open"#),
+            (11, "unterminated string".into())
+        );
+        assert_eq!(error("(level == warn"), (14, "expected `)`".into()));
+        assert_eq!(error(&"!".repeat(64)), (33, "nested too deeply".into()));
+    }
+}
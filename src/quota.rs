@@ -0,0 +1,108 @@
+use crate::{FieldValue, LogRecord};
+use parking_lot::Mutex;
+use std::{
+    borrow::Cow,
+    time::{Duration, Instant},
+};
+use tracing::Level;
+
+/// At most `max_bytes` per `period` for a sink, see
+/// [`Sink::with_quota`](crate::Sink::with_quota).
+pub(crate) struct Quota {
+    max_bytes: u64,
+    period: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Start of the current period, from the first record.
+    start: Option<Instant>,
+    used: u64,
+    suppressed: u64,
+    suppressed_bytes: u64,
+}
+
+/// A record to write to the sink, bypassing the quota.
+pub(crate) enum Notice {
+    /// The quota was just used up.
+    Exceeded { max_bytes: u64, period: Duration },
+    /// A new period started after records were suppressed.
+    Renewed { suppressed: u64, bytes: u64 },
+}
+
+impl Quota {
+    pub(crate) fn new(max_bytes: u64, period: Duration) -> Self {
+        Quota {
+            max_bytes,
+            period,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub(crate) fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Whether a record of `bytes` may be written, and a notice to write before it.
+    pub(crate) fn admit(&self, bytes: u64) -> (bool, Option<Notice>) {
+        let now = Instant::now();
+        let mut state = self.state.lock();
+        let mut notice = None;
+        match state.start {
+            Some(start) if now - start < self.period => {}
+            _ => {
+                if state.suppressed != 0 {
+                    notice = Some(Notice::Renewed {
+                        suppressed: state.suppressed,
+                        bytes: state.suppressed_bytes,
+                    });
+                }
+                *state = State {
+                    start: Some(now),
+                    ..State::default()
+                };
+            }
+        }
+
+        if state.used + bytes <= self.max_bytes {
+            state.used += bytes;
+            return (true, notice);
+        }
+        if state.suppressed == 0 {
+            notice = Some(Notice::Exceeded {
+                max_bytes: self.max_bytes,
+                period: self.period,
+            });
+        }
+        state.suppressed += 1;
+        state.suppressed_bytes += bytes;
+        (false, notice)
+    }
+}
+
+impl Notice {
+    pub(crate) fn into_record(self, time: Duration) -> LogRecord {
+        let (message, fields) = match self {
+            Notice::Exceeded { max_bytes, period } => (
+                "sink quota exceeded, suppressing records",
+                [
+                    ("quota_bytes", FieldValue::U64(max_bytes)),
+                    ("period_s", FieldValue::U64(period.as_secs())),
+                ],
+            ),
+            Notice::Renewed { suppressed, bytes } => (
+                "sink quota renewed",
+                [
+                    ("suppressed", FieldValue::U64(suppressed)),
+                    ("suppressed_bytes", FieldValue::U64(bytes)),
+                ],
+            ),
+        };
+        let mut record = LogRecord::internal(time, Level::WARN, message.to_owned());
+        let fields =
+            IntoIterator::into_iter(fields).map(|(name, value)| (Cow::Borrowed(name), value));
+        record.fields.extend(fields);
+        record
+    }
+}
@@ -0,0 +1,377 @@
+use smallvec::SmallVec;
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt::{self, Write as _},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tracing::{
+    field::{Field, Visit},
+    Level,
+};
+
+/// An event as seen by [`FmtLayer`](crate::FmtLayer), before it is formatted.
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    /// Time since the layer was created.
+    pub time: Duration,
+    /// Wall clock time of the event.
+    pub timestamp: SystemTime,
+    /// Time since the previous event, only tracked with
+    /// [`Timestamps::delta`](crate::Timestamps::delta).
+    pub delta: Option<Duration>,
+    /// Time since the innermost or root span was first entered, only tracked with
+    /// [`Timestamps::since_span`](crate::Timestamps::since_span).
+    pub since_span: Option<Duration>,
+    pub level: Level,
+    pub target: Cow<'static, str>,
+    pub module: Option<Cow<'static, str>>,
+    pub file: Option<Cow<'static, str>>,
+    pub line: Option<u32>,
+    /// The enclosing spans, outermost first.
+    pub spans: Vec<Arc<SpanRecord>>,
+    pub message: String,
+    /// All fields other than the message, in the order they were recorded.
+    pub fields: SmallVec<[(Cow<'static, str>, FieldValue); 8]>,
+    /// Id shared by all spans below the same root span.
+    pub trace_id: Option<u128>,
+    /// Id of the innermost enclosing span.
+    pub span_id: Option<u64>,
+}
+
+impl LogRecord {
+    /// A record of this crate itself, outside of any span.
+    pub(crate) fn internal(time: Duration, level: Level, message: String) -> Self {
+        LogRecord {
+            time,
+            timestamp: SystemTime::now(),
+            delta: None,
+            since_span: None,
+            level,
+            target: Cow::Borrowed(crate::TARGET),
+            module: Some(Cow::Borrowed(crate::TARGET)),
+            file: None,
+            line: None,
+            spans: Vec::new(),
+            message,
+            fields: SmallVec::new(),
+            trace_id: None,
+            span_id: None,
+        }
+    }
+
+    /// The value of the field called `name`, if the event has one.
+    pub fn field(&self, name: &str) -> Option<&FieldValue> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Fields of the event followed by those of its spans, innermost first. Fields
+    /// shadowed by one with the same name that comes earlier are skipped.
+    pub(crate) fn all_fields(&self) -> impl Iterator<Item = (&str, &FieldValue)> {
+        let mut seen = Vec::new();
+        let spans = self.spans.iter().rev().flat_map(|span| span.fields.iter());
+        self.fields
+            .iter()
+            .chain(spans)
+            .filter(move |(name, _)| {
+                if seen.contains(&&**name) {
+                    return false;
+                }
+                seen.push(&**name);
+                true
+            })
+            .map(|(name, value)| (&**name, value))
+    }
+}
+
+/// A span enclosing a [`LogRecord`], with the fields recorded on it so far.
+#[derive(Debug, Clone)]
+pub struct SpanRecord {
+    /// The name of the span, or the value of its name field, see
+    /// [`FmtLayer::with_span_name_field`](crate::FmtLayer::with_span_name_field).
+    pub name: Cow<'static, str>,
+    pub fields: SmallVec<[(Cow<'static, str>, FieldValue); 8]>,
+    /// `name{field=value ...}`, rendered whenever the fields change.
+    text: String,
+    /// Fields not stored because of [`SpanLimits::max_fields`].
+    dropped: u64,
+}
+
+impl SpanRecord {
+    pub(crate) fn new(name: Cow<'static, str>) -> Self {
+        let mut span = SpanRecord {
+            name,
+            fields: SmallVec::new(),
+            text: String::new(),
+            dropped: 0,
+        };
+        span.render();
+        span
+    }
+
+    /// A span over [`SpanLimits::max_tracked_spans`], which only keeps its name.
+    pub(crate) fn untracked(name: Cow<'static, str>) -> Self {
+        let mut span = SpanRecord::new(name);
+        span.fields
+            .push((Cow::Borrowed("untracked"), FieldValue::Bool(true)));
+        span.render();
+        span
+    }
+
+    /// Adds `fields`, replacing earlier values of fields with the same name.
+    pub(crate) fn merge(
+        &mut self,
+        fields: impl IntoIterator<Item = (Cow<'static, str>, FieldValue)>,
+        limits: &SpanLimits,
+    ) {
+        // The marker is always last.
+        if self.dropped != 0 {
+            self.fields.pop();
+        }
+        for (name, mut value) in fields {
+            limits.truncate(&mut value);
+            let len = self.fields.len();
+            match self.fields.iter_mut().find(|(field, _)| *field == name) {
+                Some((_, old)) => *old = value,
+                None if len < limits.max_fields => self.fields.push((name, value)),
+                None => self.dropped += 1,
+            }
+        }
+        if self.dropped != 0 {
+            let marker = (
+                Cow::Borrowed("dropped_fields"),
+                FieldValue::U64(self.dropped),
+            );
+            self.fields.push(marker);
+        }
+    }
+
+    /// Sorts the fields by name, keeping the `dropped_fields` marker last.
+    pub(crate) fn sort_fields(&mut self) {
+        let end = match self.dropped {
+            0 => self.fields.len(),
+            _ => self.fields.len() - 1,
+        };
+        self.fields[..end].sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+
+    pub(crate) fn render(&mut self) {
+        self.text.clear();
+        self.text.push_str(&self.name);
+        if self.fields.is_empty() {
+            return;
+        }
+        self.text.push('{');
+        for (i, (name, value)) in self.fields.iter().enumerate() {
+            if i != 0 {
+                self.text.push(' ');
+            }
+            let _ = write!(self.text, "{}={}", name, value);
+        }
+        self.text.push('}');
+    }
+
+    /// How the span appears in text output.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+/// The value of a field, in the type it was recorded with.
+///
+/// Displays like it appears in text output.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Str(String),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+    /// An error, with its `Display` output and that of its sources, outermost first.
+    Error {
+        message: String,
+        sources: Vec<String>,
+    },
+    /// Anything else, with its `Debug` output.
+    Debug(String),
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldValue::Str(value)
+            | FieldValue::Error { message: value, .. }
+            | FieldValue::Debug(value) => f.write_str(value),
+            FieldValue::I64(value) => f.write_str(itoa::Buffer::new().format(*value)),
+            FieldValue::U64(value) => f.write_str(itoa::Buffer::new().format(*value)),
+            FieldValue::F64(value) if value.is_finite() => {
+                f.write_str(ryu::Buffer::new().format_finite(*value))
+            }
+            FieldValue::F64(value) => fmt::Debug::fmt(value, f),
+            FieldValue::Bool(value) => fmt::Display::fmt(value, f),
+        }
+    }
+}
+
+impl From<&str> for FieldValue {
+    fn from(value: &str) -> Self {
+        FieldValue::Str(value.to_owned())
+    }
+}
+
+impl From<String> for FieldValue {
+    fn from(value: String) -> Self {
+        FieldValue::Str(value)
+    }
+}
+
+impl From<i64> for FieldValue {
+    fn from(value: i64) -> Self {
+        FieldValue::I64(value)
+    }
+}
+
+impl From<u64> for FieldValue {
+    fn from(value: u64) -> Self {
+        FieldValue::U64(value)
+    }
+}
+
+impl From<f64> for FieldValue {
+    fn from(value: f64) -> Self {
+        FieldValue::F64(value)
+    }
+}
+
+impl From<bool> for FieldValue {
+    fn from(value: bool) -> Self {
+        FieldValue::Bool(value)
+    }
+}
+
+/// Caps on the data [`FmtLayer`](crate::FmtLayer) keeps for every open span, see
+/// [`FmtLayer::with_span_limits`](crate::FmtLayer::with_span_limits).
+///
+/// Whatever goes over a limit is replaced by a marker: values end in `…`, spans with too
+/// many fields get a `dropped_fields` field counting the others, and spans beyond
+/// [`max_tracked_spans`](Self::max_tracked_spans) only keep their name and an
+/// `untracked=true` field.
+#[derive(Debug, Clone)]
+pub struct SpanLimits {
+    pub(crate) max_fields: usize,
+    pub(crate) max_value_bytes: usize,
+    pub(crate) max_tracked_spans: usize,
+}
+
+impl SpanLimits {
+    /// 32 fields per span, 1 KiB per value and 10000 open spans.
+    pub fn new() -> Self {
+        SpanLimits {
+            max_fields: 32,
+            max_value_bytes: 1024,
+            max_tracked_spans: 10_000,
+        }
+    }
+
+    /// Store at most `max` fields per span.
+    pub fn max_fields(mut self, max: usize) -> Self {
+        self.max_fields = max;
+        self
+    }
+
+    /// Cut field values longer than `max` bytes.
+    pub fn max_value_bytes(mut self, max: usize) -> Self {
+        self.max_value_bytes = max;
+        self
+    }
+
+    /// Store fields for at most `max` spans open at the same time.
+    pub fn max_tracked_spans(mut self, max: usize) -> Self {
+        self.max_tracked_spans = max;
+        self
+    }
+
+    fn truncate(&self, value: &mut FieldValue) {
+        let value = match value {
+            FieldValue::Str(value)
+            | FieldValue::Error { message: value, .. }
+            | FieldValue::Debug(value) => value,
+            _ => return,
+        };
+        if value.len() <= self.max_value_bytes {
+            return;
+        }
+        let mut end = self.max_value_bytes;
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        value.truncate(end);
+        value.push('…');
+    }
+}
+
+impl Default for SpanLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Collects the message and fields of an event.
+#[derive(Debug, Default)]
+pub(crate) struct RecordVisitor {
+    pub(crate) message: String,
+    pub(crate) fields: SmallVec<[(Cow<'static, str>, FieldValue); 8]>,
+}
+
+impl RecordVisitor {
+    fn push(&mut self, field: &Field, value: FieldValue) {
+        self.fields.push((Cow::Borrowed(field.name()), value));
+    }
+}
+
+impl Visit for RecordVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, FieldValue::I64(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, FieldValue::U64(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.push(field, FieldValue::F64(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, FieldValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_owned(),
+            _ => self.push(field, FieldValue::Str(value.to_owned())),
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn Error + 'static)) {
+        let mut sources = Vec::new();
+        let mut source = value.source();
+        while let Some(error) = source {
+            sources.push(error.to_string());
+            source = error.source();
+        }
+        let message = value.to_string();
+        self.push(field, FieldValue::Error { message, sources });
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => self.message = format!("{:?}", value),
+            _ => self.push(field, FieldValue::Debug(format!("{:?}", value))),
+        }
+    }
+}
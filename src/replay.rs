@@ -0,0 +1,315 @@
+//! Reading output back as [`LogRecord`]s, to filter it or convert it to another format.
+//!
+//! Lines of [`Format::Json`](crate::Format::Json) and frames of
+//! [`Sink::with_message_pack`](crate::Sink::with_message_pack) can be read back. Pass the
+//! records to [`FmtLayer::replay`](crate::FmtLayer::replay) to output them again.
+//!
+//! ```no_run
+//! # use wgpu_subscriber::{replay, FmtLayer, Format};
+//! let layer = FmtLayer::with_file("app.logfmt")?.with_format(Format::Logfmt);
+//! for record in replay::open("app.json")? {
+//!     let record = record?;
+//!     if record.level <= tracing::Level::WARN {
+//!         layer.replay(&record);
+//!     }
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use crate::{json, msgpack, FieldValue, LogRecord, SpanRecord};
+use chrono::DateTime;
+use smallvec::SmallVec;
+use std::{
+    borrow::Cow,
+    convert::TryFrom as _,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::Level;
+
+type Fields = SmallVec<[(Cow<'static, str>, FieldValue); 8]>;
+
+/// Records of [`Format::Json`](crate::Format::Json) output, one per line. Empty lines
+/// are skipped.
+pub struct JsonRecords<R> {
+    reader: R,
+    line: String,
+}
+
+/// Reads the lines of [`Format::Json`](crate::Format::Json) output from `reader`.
+pub fn json<R: BufRead>(reader: R) -> JsonRecords<R> {
+    JsonRecords {
+        reader,
+        line: String::new(),
+    }
+}
+
+impl<R: BufRead> Iterator for JsonRecords<R> {
+    type Item = io::Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(err) => return Some(Err(err)),
+            }
+            let line = self.line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            return Some(
+                json::parse(line)
+                    .as_ref()
+                    .and_then(json_record)
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::InvalidData, "invalid JSON record")
+                    }),
+            );
+        }
+    }
+}
+
+/// Records of a [`Sink::with_message_pack`](crate::Sink::with_message_pack) sink. A
+/// truncated final frame ends them.
+pub struct MessagePackRecords<R> {
+    reader: R,
+    frame: Vec<u8>,
+}
+
+/// Reads the frames of a [`Sink::with_message_pack`](crate::Sink::with_message_pack) sink
+/// from `reader`.
+pub fn message_pack<R: io::Read>(reader: R) -> MessagePackRecords<R> {
+    MessagePackRecords {
+        reader,
+        frame: Vec::new(),
+    }
+}
+
+impl<R: io::Read> Iterator for MessagePackRecords<R> {
+    type Item = io::Result<LogRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        msgpack::read_frame(&mut self.reader, &mut self.frame).transpose()
+    }
+}
+
+/// Reads the records of the file at `path`, telling JSON and MessagePack apart by its
+/// first bytes.
+pub fn open(path: impl AsRef<Path>) -> io::Result<Box<dyn Iterator<Item = io::Result<LogRecord>>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let head = reader.fill_buf()?;
+    // A MessagePack frame is a length and a map, JSON lines start with `{"`.
+    let is_message_pack = !head.starts_with(b"{\"")
+        && head
+            .get(4)
+            .is_some_and(|tag| matches!(tag, 0x80..=0x8f | 0xde | 0xdf));
+    Ok(match is_message_pack {
+        true => Box::new(message_pack(reader)),
+        false => Box::new(json(reader)),
+    })
+}
+
+fn fields(value: &json::Value) -> Fields {
+    value
+        .members()
+        .map(|(name, value)| {
+            let value = match value {
+                json::Value::Str(value) => FieldValue::Str(value.clone()),
+                json::Value::U64(value) => FieldValue::U64(*value),
+                json::Value::I64(value) => FieldValue::I64(*value),
+                json::Value::F64(value) => FieldValue::F64(*value),
+                json::Value::Bool(value) => FieldValue::Bool(*value),
+                value => FieldValue::Debug(format!("{:?}", value)),
+            };
+            (Cow::Owned(name.to_owned()), value)
+        })
+        .collect()
+}
+
+fn json_record(value: &json::Value) -> Option<LogRecord> {
+    use json::Value;
+
+    let mut record = LogRecord {
+        time: Duration::ZERO,
+        timestamp: UNIX_EPOCH,
+        delta: None,
+        since_span: None,
+        level: Level::INFO,
+        target: Cow::Borrowed(""),
+        module: None,
+        file: None,
+        line: None,
+        spans: Vec::new(),
+        message: String::new(),
+        fields: SmallVec::new(),
+        trace_id: None,
+        span_id: None,
+    };
+    for (key, value) in value.members() {
+        match (key, value) {
+            ("timestamp", Value::Str(timestamp)) => {
+                record.timestamp = SystemTime::from(DateTime::parse_from_rfc3339(timestamp).ok()?);
+            }
+            ("elapsed_us", Value::U64(micros)) => record.time = Duration::from_micros(*micros),
+            ("level", Value::Str(level)) => record.level = level.parse().ok()?,
+            ("target", Value::Str(target)) => record.target = Cow::Owned(target.clone()),
+            ("module", Value::Str(module)) => record.module = Some(Cow::Owned(module.clone())),
+            ("file", Value::Str(file)) => record.file = Some(Cow::Owned(file.clone())),
+            ("line", Value::U64(line)) => record.line = u32::try_from(*line).ok(),
+            ("spans", Value::Array(spans)) => {
+                for span in spans {
+                    let mut name = "";
+                    let mut span_fields = SmallVec::new();
+                    for (key, value) in span.members() {
+                        match (key, value) {
+                            ("name", Value::Str(value)) => name = value,
+                            ("fields", value) => span_fields = fields(value),
+                            _ => {}
+                        }
+                    }
+                    let mut span = SpanRecord::new(Cow::Owned(name.to_owned()));
+                    span.fields = span_fields;
+                    span.render();
+                    record.spans.push(Arc::new(span));
+                }
+            }
+            ("message", Value::Str(message)) => record.message = message.clone(),
+            ("fields", value) => record.fields = fields(value),
+            ("trace_id", Value::Str(id)) => record.trace_id = u128::from_str_radix(id, 16).ok(),
+            ("span_id", Value::Str(id)) => record.span_id = u64::from_str_radix(id, 16).ok(),
+            _ => {}
+        }
+    }
+    Some(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{format::FormatOptions, FmtLayer, Format, LevelMapping};
+
+    fn record() -> LogRecord {
+        let mut span = SpanRecord::new(Cow::Borrowed("request"));
+        span.fields.push((Cow::Borrowed("id"), FieldValue::U64(7)));
+        span.render();
+
+        let mut record =
+            LogRecord::internal(Duration::from_micros(1500), Level::WARN, "slow".into());
+        record.timestamp = UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        record.target = Cow::Borrowed("app::db");
+        record.module = Some(Cow::Borrowed("app::db"));
+        record.file = Some(Cow::Borrowed("src/db.rs"));
+        record.line = Some(12);
+        record.spans.push(Arc::new(span));
+        record.fields.extend([
+            (Cow::Borrowed("str"), FieldValue::Str("a \"b\"".to_owned())),
+            (Cow::Borrowed("i64"), FieldValue::I64(-3)),
+            (Cow::Borrowed("u64"), FieldValue::U64(u64::MAX)),
+            (Cow::Borrowed("f64"), FieldValue::F64(0.25)),
+            (Cow::Borrowed("bool"), FieldValue::Bool(true)),
+        ]);
+        record.trace_id = Some(0x0123_4567_89ab_cdef_0123_4567_89ab_cdef);
+        record.span_id = Some(0xfedc_ba98_7654_3210);
+        record
+    }
+
+    fn assert_same(read: &LogRecord, written: &LogRecord) {
+        assert_eq!(read.time, written.time);
+        assert_eq!(read.timestamp, written.timestamp);
+        assert_eq!(read.level, written.level);
+        assert_eq!(read.target, written.target);
+        assert_eq!(read.module, written.module);
+        assert_eq!(read.file, written.file);
+        assert_eq!(read.line, written.line);
+        assert_eq!(read.message, written.message);
+        assert_eq!(read.fields, written.fields);
+        assert_eq!(read.spans.len(), written.spans.len());
+        for (read, written) in read.spans.iter().zip(&written.spans) {
+            assert_eq!(read.name, written.name);
+            assert_eq!(read.fields, written.fields);
+        }
+        assert_eq!(read.trace_id, written.trace_id);
+        assert_eq!(read.span_id, written.span_id);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let written = record();
+        let mut out = String::new();
+        for _ in 0..2 {
+            let mut line = String::new();
+            Format::Json.write(&written, &FormatOptions::default(), &mut line);
+            out.push_str(&line);
+            out.push_str("\n\n");
+        }
+
+        let records: Vec<_> = json(out.as_bytes()).collect::<io::Result<_>>().unwrap();
+        assert_eq!(records.len(), 2);
+        for read in &records {
+            assert_same(read, &written);
+        }
+        let error = json(&b"{\"level\":\"LOUD\"}\n"[..]).next().unwrap();
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn message_pack_round_trip() {
+        let written = record();
+        let mut out = Vec::new();
+        for _ in 0..2 {
+            let start = out.len();
+            out.extend_from_slice(&[0; 4]);
+            msgpack::encode(&written, &mut out);
+            let len = (out.len() - start - 4) as u32;
+            out[start..start + 4].copy_from_slice(&len.to_le_bytes());
+        }
+        // A truncated final frame is ignored.
+        out.extend_from_slice(&[9, 0, 0, 0, 0x80]);
+
+        let records: Vec<_> = message_pack(&out[..]).collect::<io::Result<_>>().unwrap();
+        assert_eq!(records.len(), 2);
+        for read in &records {
+            assert_same(read, &written);
+        }
+        let error = message_pack(&[u8::MAX; 8][..]).next().unwrap();
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn message_pack_drops_oversized_records() {
+        let path = std::env::temp_dir().join(format!("oversized-{}.msgpack", std::process::id()));
+        let (layer, _snapshot) = FmtLayer::with_snapshot();
+        let layer = layer.with_sink(crate::Sink::file(&path).unwrap().with_message_pack());
+        let written = record();
+        let mut oversized = record();
+        oversized.message = "x".repeat(msgpack::MAX_FRAME);
+
+        let dropped = crate::dropped_events().write_errors;
+        layer.replay(&written);
+        layer.replay(&oversized);
+        layer.replay(&written);
+        drop(layer);
+        assert!(crate::dropped_events().write_errors > dropped);
+
+        let records = msgpack::read_message_pack_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        for read in &records {
+            assert_eq!(read.message, written.message);
+        }
+    }
+
+    #[test]
+    fn replay_maps_levels() {
+        let (layer, snapshot) = FmtLayer::with_snapshot();
+        let layer =
+            layer.with_level_mapping(LevelMapping::new().remap("app", Level::WARN, Level::INFO));
+        layer.replay(&record());
+        assert!(snapshot.take().starts_with("[0.000000 INFO]"));
+    }
+}
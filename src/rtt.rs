@@ -0,0 +1,35 @@
+use crate::bare::ByteSink;
+use core::cell::RefCell;
+use critical_section::Mutex;
+use rtt_target::UpChannel;
+
+/// A [`ByteSink`] writing to an RTT up channel.
+///
+/// Writes happen inside a critical section, so events from interrupt handlers don't
+/// tear lines apart. Whether a full channel blocks or drops bytes is up to the
+/// channel's mode.
+///
+/// ```ignore
+/// let channels = rtt_target::rtt_init! { up: { 0: { size: 1024, name: "Log" } } };
+/// let subscriber = BareSubscriber::new(RttSink::new(channels.up.0), Clock)
+///     .with_encoding(Encoding::Compact);
+/// ```
+pub struct RttSink {
+    channel: Mutex<RefCell<UpChannel>>,
+}
+
+impl RttSink {
+    pub fn new(channel: UpChannel) -> Self {
+        RttSink {
+            channel: Mutex::new(RefCell::new(channel)),
+        }
+    }
+}
+
+impl ByteSink for RttSink {
+    fn write_bytes(&self, bytes: &[u8]) {
+        critical_section::with(|cs| {
+            self.channel.borrow_ref_mut(cs).write(bytes);
+        });
+    }
+}
@@ -0,0 +1,191 @@
+use crate::{
+    stats::{record_drop, DropReason},
+    util::random_u64,
+};
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{span, subscriber::Interest, Metadata, Subscriber};
+use tracing_subscriber::{
+    layer::Context,
+    registry::{LookupSpan, SpanRef},
+    Layer,
+};
+
+/// Sampling decision of a span, inherited from its root span.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Sampled(bool);
+
+/// Whether `span` belongs to a trace that was kept. Spans seen without a
+/// [`SamplingLayer`] are always kept.
+pub(crate) fn is_sampled<R>(span: &SpanRef<'_, R>) -> bool
+where
+    R: for<'span> LookupSpan<'span>,
+{
+    !matches!(span.extensions().get::<Sampled>(), Some(Sampled(false)))
+}
+
+enum Strategy {
+    Probability(f64),
+    PerSecond {
+        limit: u32,
+        window: Mutex<(Instant, u32)>,
+    },
+}
+
+/// Head based sampling of whole traces.
+///
+/// The decision is made once, when a root span is created, and is inherited by
+/// every span below it. Spans and events inside a dropped trace are disabled for
+/// all layers, so every output sees the same set of complete traces. Events
+/// outside of any span are always kept.
+///
+/// Add it before the other layers. It has to check every callsite each time it
+/// is hit, and added last it would hide the callsites that filters after it
+/// disable for good, so they would be checked each time too.
+///
+/// ```no_run
+/// # use tracing_subscriber::layer::SubscriberExt as _;
+/// # use wgpu_subscriber::{FmtLayer, SamplingLayer};
+/// let subscriber = tracing_subscriber::Registry::default()
+///     .with(SamplingLayer::probability(0.1))
+///     .with(FmtLayer::new());
+/// ```
+pub struct SamplingLayer {
+    strategy: Strategy,
+}
+
+impl SamplingLayer {
+    /// Keep each trace with the given probability, between 0 and 1.
+    pub fn probability(probability: f64) -> Self {
+        SamplingLayer {
+            strategy: Strategy::Probability(probability),
+        }
+    }
+
+    /// Keep at most `limit` traces per second, dropping the rest.
+    pub fn per_second(limit: u32) -> Self {
+        SamplingLayer {
+            strategy: Strategy::PerSecond {
+                limit,
+                window: Mutex::new((Instant::now(), 0)),
+            },
+        }
+    }
+
+    fn sample(&self) -> bool {
+        match self.strategy {
+            Strategy::Probability(probability) => {
+                probability >= 1.0 || (random_u64() as f64) < probability * u64::MAX as f64
+            }
+            Strategy::PerSecond { limit, ref window } => {
+                let now = Instant::now();
+                let mut window = window.lock();
+                if now - window.0 >= Duration::from_secs(1) {
+                    *window = (now, 0);
+                }
+                if window.1 < limit {
+                    window.1 += 1;
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+}
+
+impl<S> Layer<S> for SamplingLayer
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        if !crate::statically_enabled(metadata.level()) {
+            return Interest::never();
+        }
+        // Whether a callsite is enabled depends on the trace it is hit in.
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        let sampled = match ctx.lookup_current() {
+            Some(span) => is_sampled(&span),
+            None => true,
+        };
+        if !sampled && metadata.is_event() {
+            record_drop(DropReason::HeadSampling, 1);
+        }
+        sampled
+    }
+
+    fn new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).unwrap();
+        let sampled = match span.parent() {
+            Some(parent) => is_sampled(&parent),
+            None => self.sample(),
+        };
+        span.extensions_mut().replace(Sampled(sampled));
+    }
+}
+
+/// Tail based sampling, see [`FmtLayer::with_tail_sampling`](crate::FmtLayer::with_tail_sampling).
+///
+/// A trace is kept if it logged an ERROR event, recorded an `error` field on
+/// one of its spans, or took longer than the latency threshold.
+///
+/// At most 1 000 events are held back per trace and 100 000 in total by default, see
+/// [`TailSampling::max_buffered`].
+#[derive(Debug, Clone)]
+pub struct TailSampling {
+    pub(crate) latency_threshold: Option<Duration>,
+    pub(crate) always_keep: Option<tracing::Level>,
+    pub(crate) max_per_trace: usize,
+    pub(crate) max_total: usize,
+}
+
+impl Default for TailSampling {
+    fn default() -> Self {
+        Self {
+            latency_threshold: None,
+            always_keep: None,
+            max_per_trace: 1_000,
+            max_total: 100_000,
+        }
+    }
+}
+
+impl TailSampling {
+    /// Keep only traces with errors.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also keep traces whose root span was open for longer than `threshold`.
+    pub fn latency_threshold(mut self, threshold: Duration) -> Self {
+        self.latency_threshold = Some(threshold);
+        self
+    }
+
+    /// Output events at `level` or above even when their trace is dropped.
+    pub fn always_keep(mut self, level: tracing::Level) -> Self {
+        self.always_keep = Some(level);
+        self
+    }
+
+    /// Hold back at most `per_trace` events of a trace and `total` events of all traces,
+    /// so long lived root spans like that of `main` or of a connection don't grow memory
+    /// without bounds.
+    ///
+    /// Past that, the oldest held back event is dropped, or output right away if it is at
+    /// the level of [`TailSampling::always_keep`].
+    pub fn max_buffered(mut self, per_trace: usize, total: usize) -> Self {
+        self.max_per_trace = per_trace;
+        self.max_total = total;
+        self
+    }
+
+    /// Whether events at `level` are output even when their trace is dropped.
+    pub(crate) fn always_keeps(&self, level: &tracing::Level) -> bool {
+        self.always_keep
+            .is_some_and(|always_keep| *level <= always_keep)
+    }
+}
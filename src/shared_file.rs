@@ -0,0 +1,189 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write as _},
+    path::Path,
+};
+
+/// Buffered records are written once they reach this size, even without a flush.
+const CAPACITY: usize = 64 * 1024;
+
+/// A file other processes append to as well.
+///
+/// Only whole records are buffered, and they are appended with a single write while holding
+/// an exclusive advisory lock on the file, so records of different processes never mix.
+/// Processes writing to the file without locking it can still interleave with them.
+pub(crate) struct SharedFile {
+    file: File,
+    buffer: Vec<u8>,
+}
+
+impl SharedFile {
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(SharedFile {
+            file,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Bytes in the file, including those still buffered.
+    pub(crate) fn len(&self) -> u64 {
+        let written = self.file.metadata().map_or(0, |metadata| metadata.len());
+        written + self.buffer.len() as u64
+    }
+
+    pub(crate) fn write_line(&mut self, line: &str, flush: bool) -> io::Result<()> {
+        self.buffer.extend_from_slice(line.as_bytes());
+        self.buffer.push(b'\n');
+        self.written(flush)
+    }
+
+    pub(crate) fn write_frame(&mut self, frame: &[u8], flush: bool) -> io::Result<()> {
+        self.buffer.extend_from_slice(frame);
+        self.written(flush)
+    }
+
+    /// Writes the buffer after a complete record was added, if `flush` or if it is full.
+    fn written(&mut self, flush: bool) -> io::Result<()> {
+        match flush || self.buffer.len() >= CAPACITY {
+            true => self.flush(),
+            false => Ok(()),
+        }
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        lock(&self.file)?;
+        let written = self.file.write_all(&self.buffer);
+        let unlocked = unlock(&self.file);
+        // Records that failed to be written are dropped rather than retried, which could
+        // duplicate a partially written one.
+        self.buffer.clear();
+        written.and(unlocked)
+    }
+}
+
+impl Drop for SharedFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Takes an exclusive advisory lock on `file`, waiting for other processes to release it.
+#[cfg(unix)]
+fn lock(file: &File) -> io::Result<()> {
+    flock(file, libc::LOCK_EX)
+}
+
+#[cfg(unix)]
+fn unlock(file: &File) -> io::Result<()> {
+    flock(file, libc::LOCK_UN)
+}
+
+#[cfg(unix)]
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd as _;
+
+    loop {
+        // SAFETY: the descriptor stays open as long as `file` is borrowed.
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Takes an exclusive lock on all of `file`, waiting for other processes to release it.
+#[cfg(windows)]
+fn lock(file: &File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle as _;
+
+    let mut overlapped = windows::Overlapped::default();
+    // SAFETY: the handle stays open as long as `file` is borrowed, and the call is
+    // synchronous so `overlapped` outlives it.
+    let locked = unsafe {
+        windows::LockFileEx(
+            file.as_raw_handle(),
+            windows::LOCKFILE_EXCLUSIVE_LOCK,
+            0,
+            u32::MAX,
+            u32::MAX,
+            &mut overlapped,
+        )
+    };
+    match locked {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(windows)]
+fn unlock(file: &File) -> io::Result<()> {
+    use std::os::windows::io::AsRawHandle as _;
+
+    let mut overlapped = windows::Overlapped::default();
+    // SAFETY: see `lock`.
+    let unlocked = unsafe {
+        windows::UnlockFileEx(file.as_raw_handle(), 0, u32::MAX, u32::MAX, &mut overlapped)
+    };
+    match unlocked {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// The parts of `kernel32` used to lock files.
+#[cfg(windows)]
+mod windows {
+    use std::{ffi::c_void, ptr};
+
+    pub(super) const LOCKFILE_EXCLUSIVE_LOCK: u32 = 2;
+
+    /// `OVERLAPPED`, only its offset is read, which is that of the locked range.
+    #[allow(dead_code)]
+    #[repr(C)]
+    pub(super) struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: *mut c_void,
+    }
+
+    impl Default for Overlapped {
+        fn default() -> Self {
+            Overlapped {
+                internal: 0,
+                internal_high: 0,
+                offset: 0,
+                offset_high: 0,
+                event: ptr::null_mut(),
+            }
+        }
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        pub(super) fn LockFileEx(
+            file: *mut c_void,
+            flags: u32,
+            reserved: u32,
+            bytes_low: u32,
+            bytes_high: u32,
+            overlapped: *mut Overlapped,
+        ) -> i32;
+        pub(super) fn UnlockFileEx(
+            file: *mut c_void,
+            reserved: u32,
+            bytes_low: u32,
+            bytes_high: u32,
+            overlapped: *mut Overlapped,
+        ) -> i32;
+    }
+}
@@ -0,0 +1,31 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+/// The lines written by a [`FmtLayer::with_snapshot`](crate::FmtLayer::with_snapshot) so
+/// far, to compare with a stored snapshot, for example with `insta::assert_snapshot!`.
+///
+/// See [`capture_snapshot`](crate::capture_snapshot) for the common case of a single
+/// closure.
+#[derive(Clone, Default)]
+pub struct Snapshot {
+    text: Arc<Mutex<String>>,
+}
+
+impl Snapshot {
+    pub(crate) fn push(&self, line: &str) {
+        let mut text = self.text.lock();
+        text.push_str(line);
+        text.push('\n');
+    }
+
+    /// Every line captured so far, each ending with a newline.
+    pub fn contents(&self) -> String {
+        self.text.lock().clone()
+    }
+
+    /// Like [`Snapshot::contents`], but clears the lines, so the next call only returns
+    /// those written after this one.
+    pub fn take(&self) -> String {
+        std::mem::take(&mut *self.text.lock())
+    }
+}
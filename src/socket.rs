@@ -0,0 +1,169 @@
+use crate::{spool::Spool, writer::SinkEvent, FieldValue};
+use std::{
+    io::{self, Write as _},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+#[cfg(unix)]
+type Stream = std::os::unix::net::UnixStream;
+#[cfg(windows)]
+type Stream = std::fs::File;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Streams lines into a Unix domain socket or Windows named pipe, reconnecting
+/// with exponential backoff whenever the other side goes away.
+pub(crate) struct SocketWriter {
+    path: PathBuf,
+    stream: Option<Stream>,
+    next_attempt: Instant,
+    backoff: Duration,
+    /// Where lines go while disconnected, instead of being dropped.
+    spool: Option<Spool>,
+    /// Failed connection attempts since the last connection.
+    attempts: u64,
+    connected_before: bool,
+    /// Set from the first line spooled or dropped since the connection was lost.
+    spooling: bool,
+    events: Vec<SinkEvent>,
+    /// Process the connection belongs to, a forked child connects again.
+    pid: u32,
+}
+
+impl SocketWriter {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        SocketWriter {
+            path,
+            stream: None,
+            next_attempt: Instant::now(),
+            backoff: MIN_BACKOFF,
+            spool: None,
+            attempts: 0,
+            connected_before: false,
+            spooling: false,
+            events: Vec::new(),
+            pid: std::process::id(),
+        }
+    }
+
+    pub(crate) fn with_spool(path: PathBuf, spool: Spool) -> Self {
+        SocketWriter {
+            spool: Some(spool),
+            ..Self::new(path)
+        }
+    }
+
+    #[cfg(unix)]
+    fn connect(&self) -> io::Result<Stream> {
+        Stream::connect(&self.path)
+    }
+
+    #[cfg(windows)]
+    fn connect(&self) -> io::Result<Stream> {
+        std::fs::OpenOptions::new().write(true).open(&self.path)
+    }
+
+    fn ensure_connected(&mut self) -> io::Result<()> {
+        let pid = std::process::id();
+        if pid != self.pid {
+            self.pid = pid;
+            self.stream = None;
+            self.next_attempt = Instant::now();
+            self.backoff = MIN_BACKOFF;
+        }
+        if self.stream.is_none() {
+            let now = Instant::now();
+            if now < self.next_attempt {
+                return Err(io::ErrorKind::NotConnected.into());
+            }
+            match self.connect() {
+                Ok(stream) => {
+                    if self.connected_before || self.attempts != 0 {
+                        let event = SinkEvent::new("sink connected", &self.path)
+                            .with_field("attempts", FieldValue::U64(self.attempts + 1));
+                        self.events.push(event);
+                    }
+                    self.stream = Some(stream);
+                    self.backoff = MIN_BACKOFF;
+                    self.attempts = 0;
+                    self.connected_before = true;
+                    self.spooling = false;
+                }
+                Err(err) => {
+                    if self.attempts == 0 && !self.connected_before {
+                        let event = SinkEvent::new("sink unreachable", &self.path)
+                            .with_field("error", FieldValue::Str(err.to_string()));
+                        self.events.push(event);
+                    }
+                    self.next_attempt = now + self.backoff;
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                    self.attempts += 1;
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a line, or fails if there is no connection right now. With a spool, the
+    /// line is spooled instead, and spooled lines are sent first once connected.
+    pub(crate) fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let mut buffer = Vec::with_capacity(line.len() + 1);
+        buffer.extend_from_slice(line.as_bytes());
+        buffer.push(b'\n');
+        self.write_bytes(&buffer)
+    }
+
+    /// Like [`SocketWriter::write_line`], for bytes sent as they are.
+    pub(crate) fn write_bytes(&mut self, buffer: &[u8]) -> io::Result<()> {
+        let result = match (self.send(buffer), &mut self.spool) {
+            (Err(_), Some(spool)) => spool.push(buffer).inspect_err(|_| {
+                if !self.spooling {
+                    self.events.push(SinkEvent::new(
+                        "sink spool full, dropping lines",
+                        &self.path,
+                    ));
+                }
+                self.spooling = true;
+            }),
+            (result, _) => result,
+        };
+        if result.is_ok() && self.stream.is_none() && !self.spooling {
+            self.spooling = true;
+            self.events
+                .push(SinkEvent::new("sink spooling lines", &self.path));
+        }
+        result
+    }
+
+    /// Moves events since the last call to `out`.
+    pub(crate) fn take_events(&mut self, out: &mut Vec<SinkEvent>) {
+        out.append(&mut self.events);
+    }
+
+    fn send(&mut self, buffer: &[u8]) -> io::Result<()> {
+        self.ensure_connected()?;
+        let stream = self.stream.as_mut().unwrap();
+        let result = match self.spool {
+            Some(ref mut spool) => spool.replay(stream),
+            None => Ok(0),
+        };
+        if let Ok(bytes @ 1..) = result {
+            let event = SinkEvent::new("sink replayed spooled lines", &self.path)
+                .with_field("bytes", FieldValue::U64(bytes));
+            self.events.push(event);
+        }
+        let result = result.and_then(|_| stream.write_all(buffer));
+        if let Err(ref err) = result {
+            // Reconnect on the next line.
+            self.stream = None;
+            self.spooling = false;
+            let event = SinkEvent::new("sink disconnected", &self.path)
+                .with_field("error", FieldValue::Str(err.to_string()));
+            self.events.push(event);
+        }
+        result
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read as _, Seek as _, SeekFrom, Write},
+    path::Path,
+};
+
+/// Lines or frames a socket couldn't send, kept on disk until it reconnects.
+///
+/// The file is left in place, so lines spooled before a restart are sent by the next
+/// process using it. Only its length is known then, so what is sent is counted in bytes.
+pub(crate) struct Spool {
+    file: File,
+    len: u64,
+    max_bytes: u64,
+}
+
+impl Spool {
+    pub(crate) fn open(path: &Path, max_bytes: u64) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Spool {
+            len: file.metadata()?.len(),
+            file,
+            max_bytes,
+        })
+    }
+
+    /// Appends `line`, failing when the spool is full.
+    pub(crate) fn push(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.len + line.len() as u64 > self.max_bytes {
+            return Err(io::Error::other("spool is full"));
+        }
+        self.file.seek(SeekFrom::Start(self.len))?;
+        self.file.write_all(line)?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    /// Sends all spooled lines to `out`, oldest first, and empties the spool. Returns the
+    /// number of bytes sent.
+    ///
+    /// If this fails midway, the lines are sent again on the next attempt, so some may
+    /// arrive twice. The line or binary frame it stopped in is left cut on the other side,
+    /// where a MessagePack or protobuf reader can't find the start of the next frame.
+    pub(crate) fn replay(&mut self, out: &mut impl Write) -> io::Result<u64> {
+        if self.len == 0 {
+            return Ok(0);
+        }
+        self.file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut (&self.file).take(self.len), out)?;
+        self.file.set_len(0)?;
+        Ok(std::mem::take(&mut self.len))
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static HEAD_SAMPLING: AtomicU64 = AtomicU64::new(0);
+static TAIL_SAMPLING: AtomicU64 = AtomicU64::new(0);
+static WRITE_ERRORS: AtomicU64 = AtomicU64::new(0);
+static STORM_PROTECTION: AtomicU64 = AtomicU64::new(0);
+static QUOTA: AtomicU64 = AtomicU64::new(0);
+
+/// Why an event never made it to an output.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum DropReason {
+    HeadSampling,
+    TailSampling,
+    WriteError,
+    StormProtection,
+    Quota,
+}
+
+pub(crate) fn record_drop(reason: DropReason, count: u64) {
+    let counter = match reason {
+        DropReason::HeadSampling => &HEAD_SAMPLING,
+        DropReason::TailSampling => &TAIL_SAMPLING,
+        DropReason::WriteError => &WRITE_ERRORS,
+        DropReason::StormProtection => &STORM_PROTECTION,
+        DropReason::Quota => &QUOTA,
+    };
+    counter.fetch_add(count, Ordering::Relaxed);
+}
+
+/// Number of events dropped by this crate since the process started, by reason.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct DroppedEvents {
+    /// Events inside traces discarded by a [`SamplingLayer`](crate::SamplingLayer).
+    pub head_sampling: u64,
+    /// Events inside traces discarded by [`TailSampling`](crate::TailSampling).
+    pub tail_sampling: u64,
+    /// Events whose output failed to be written, or that were too long for a
+    /// [`Sink::with_message_pack`](crate::Sink::with_message_pack) frame.
+    pub write_errors: u64,
+    /// Events below WARN held back during a log storm, see
+    /// [`StormProtection`](crate::StormProtection).
+    pub storm_protection: u64,
+    /// Records not written to a sink over its quota, once per sink, see
+    /// [`Sink::with_quota`](crate::Sink::with_quota).
+    pub quota: u64,
+}
+
+impl DroppedEvents {
+    pub fn total(&self) -> u64 {
+        self.head_sampling
+            + self.tail_sampling
+            + self.write_errors
+            + self.storm_protection
+            + self.quota
+    }
+
+    /// Counts accumulated since `earlier`.
+    pub(crate) fn since(&self, earlier: &DroppedEvents) -> DroppedEvents {
+        DroppedEvents {
+            head_sampling: self.head_sampling - earlier.head_sampling,
+            tail_sampling: self.tail_sampling - earlier.tail_sampling,
+            write_errors: self.write_errors - earlier.write_errors,
+            storm_protection: self.storm_protection - earlier.storm_protection,
+            quota: self.quota - earlier.quota,
+        }
+    }
+
+    /// Human readable description, e.g. `42 events dropped (write errors)`.
+    pub(crate) fn describe(&self) -> String {
+        [
+            (self.head_sampling, "head sampling"),
+            (self.tail_sampling, "tail sampling"),
+            (self.write_errors, "write errors"),
+            (self.storm_protection, "storm protection"),
+            (self.quota, "sink quota"),
+        ]
+        .iter()
+        .filter(|(count, _)| *count != 0)
+        .map(|(count, reason)| format!("{} events dropped ({})", count, reason))
+        .collect::<Vec<_>>()
+        .join(", ")
+    }
+}
+
+/// Events dropped by this crate so far, across all layers.
+pub fn dropped_events() -> DroppedEvents {
+    DroppedEvents {
+        head_sampling: HEAD_SAMPLING.load(Ordering::Relaxed),
+        tail_sampling: TAIL_SAMPLING.load(Ordering::Relaxed),
+        write_errors: WRITE_ERRORS.load(Ordering::Relaxed),
+        storm_protection: STORM_PROTECTION.load(Ordering::Relaxed),
+        quota: QUOTA.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,97 @@
+use parking_lot::Mutex;
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Protection against log storms, see
+/// [`FmtLayer::with_storm_protection`](crate::FmtLayer::with_storm_protection).
+///
+/// When more than `events_per_second` events reach the layer every second for
+/// `duration`, only WARN and ERROR events are output until a second stays under the
+/// limit again. Both changes are announced by a WARN record.
+#[derive(Debug)]
+pub struct StormProtection {
+    events_per_second: u64,
+    duration: Duration,
+    /// Events counted in the current window.
+    count: AtomicU64,
+    /// Start of the current window, in microseconds since the layer was created.
+    window_start: AtomicU64,
+    degraded: AtomicBool,
+    suppressed: AtomicU64,
+    /// When the rate first went over the limit, held while evaluating a window.
+    over_since: Mutex<Option<Duration>>,
+}
+
+/// A change of [`StormProtection`] to report.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum StormChange {
+    Started { events_per_second: u64 },
+    Ended { suppressed: u64 },
+}
+
+impl StormProtection {
+    pub fn new(events_per_second: u64, duration: Duration) -> Self {
+        StormProtection {
+            events_per_second,
+            duration,
+            count: AtomicU64::new(0),
+            window_start: AtomicU64::new(0),
+            degraded: AtomicBool::new(false),
+            suppressed: AtomicU64::new(0),
+            over_since: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn events_per_second(&self) -> u64 {
+        self.events_per_second
+    }
+
+    pub(crate) fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn suppress(&self) {
+        self.suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an event at `time` since the layer was created, and evaluates the rate once a
+    /// window of one second is over.
+    pub(crate) fn observe(&self, time: Duration) -> Option<StormChange> {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let now = time.as_micros() as u64;
+        let start = self.window_start.load(Ordering::Relaxed);
+        if now.saturating_sub(start) < 1_000_000 {
+            return None;
+        }
+
+        // Another thread is already evaluating this window.
+        let mut over_since = self.over_since.try_lock()?;
+        if self.window_start.load(Ordering::Relaxed) != start {
+            return None;
+        }
+        let count = self.count.swap(0, Ordering::Relaxed);
+        self.window_start.store(now, Ordering::Relaxed);
+
+        let rate = count * 1_000_000 / (now - start);
+        if rate > self.events_per_second {
+            let since = *over_since.get_or_insert(Duration::from_micros(start));
+            if !self.is_degraded() && time - since >= self.duration {
+                self.degraded.store(true, Ordering::Relaxed);
+                return Some(StormChange::Started {
+                    events_per_second: rate,
+                });
+            }
+        } else {
+            *over_since = None;
+            if self.is_degraded() {
+                self.degraded.store(false, Ordering::Relaxed);
+                return Some(StormChange::Ended {
+                    suppressed: self.suppressed.swap(0, Ordering::Relaxed),
+                });
+            }
+        }
+        None
+    }
+}
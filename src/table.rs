@@ -0,0 +1,134 @@
+use crate::LogRecord;
+use parking_lot::Mutex;
+use std::{collections::HashMap, fmt::Write as _};
+use tracing::Level;
+
+/// Distinct rows kept, further ones are counted together.
+const MAX_ROWS: usize = 1000;
+/// Rows shown, the others are summed up in one line.
+const SHOWN_ROWS: usize = 20;
+
+/// What the rows of [`FmtLayer::with_problem_table`](crate::FmtLayer::with_problem_table)
+/// are.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GroupBy {
+    /// One row per level and target.
+    Target,
+    /// One row per level and message, for tools whose messages don't vary much.
+    Message,
+}
+
+/// Warnings and errors of a layer counted per group, printed by its
+/// [`FmtGuard`](crate::FmtGuard).
+#[derive(Debug)]
+pub(crate) struct ProblemTable {
+    group_by: GroupBy,
+    /// ERROR before WARN, like [`Level`] orders them.
+    rows: Mutex<HashMap<(Level, String), u64>>,
+    /// Events beyond [`MAX_ROWS`].
+    other: Mutex<u64>,
+}
+
+impl ProblemTable {
+    pub(crate) fn new(group_by: GroupBy) -> Self {
+        ProblemTable {
+            group_by,
+            rows: Mutex::new(HashMap::new()),
+            other: Mutex::new(0),
+        }
+    }
+
+    pub(crate) fn count(&self, record: &LogRecord) {
+        if record.level > Level::WARN {
+            return;
+        }
+        let key = match self.group_by {
+            GroupBy::Target => &*record.target,
+            GroupBy::Message => record.message.lines().next().unwrap_or_default(),
+        };
+        let mut rows = self.rows.lock();
+        if let Some(count) = rows.get_mut(&(record.level, key.to_owned())) {
+            *count += 1;
+        } else if rows.len() < MAX_ROWS {
+            rows.insert((record.level, key.to_owned()), 1);
+        } else {
+            *self.other.lock() += 1;
+        }
+    }
+
+    /// The table with aligned columns, nothing if there were no warnings or errors.
+    ///
+    /// ```text
+    /// warnings and errors:
+    ///   LEVEL  COUNT  TARGET
+    ///   ERROR      2  payments::card
+    ///   WARN      13  hyper
+    /// ```
+    pub(crate) fn render(&self) -> String {
+        let mut rows: Vec<_> = self
+            .rows
+            .lock()
+            .iter()
+            .map(|((level, key), count)| (*level, key.clone(), *count))
+            .collect();
+        let mut other = *self.other.lock();
+        if rows.is_empty() && other == 0 {
+            return String::new();
+        }
+        rows.sort_by(|(a_level, a_key, a_count), (b_level, b_key, b_count)| {
+            a_level
+                .cmp(b_level)
+                .then(b_count.cmp(a_count))
+                .then(a_key.cmp(b_key))
+        });
+        if rows.len() > SHOWN_ROWS {
+            other += rows
+                .drain(SHOWN_ROWS..)
+                .map(|(_, _, count)| count)
+                .sum::<u64>();
+        }
+
+        let header = match self.group_by {
+            GroupBy::Target => "TARGET",
+            GroupBy::Message => "MESSAGE",
+        };
+        let counts: Vec<_> = rows.iter().map(|(_, _, count)| count.to_string()).collect();
+        let width = counts
+            .iter()
+            .map(String::len)
+            .chain(Some(other.to_string().len()))
+            .max()
+            .unwrap_or(0)
+            .max("COUNT".len());
+
+        let mut out = String::from("warnings and errors:\n");
+        let _ = writeln!(
+            out,
+            "  LEVEL  {:>width$}  {}",
+            "COUNT",
+            header,
+            width = width
+        );
+        for ((level, key, _), count) in rows.iter().zip(&counts) {
+            let level = crate::bare::level_str(level);
+            let _ = writeln!(
+                out,
+                "  {:<5}  {:>width$}  {}",
+                level,
+                count,
+                key,
+                width = width
+            );
+        }
+        if other != 0 {
+            let _ = writeln!(
+                out,
+                "  {:<5}  {:>width$}  (others)",
+                "",
+                other,
+                width = width
+            );
+        }
+        out
+    }
+}
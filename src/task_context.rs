@@ -0,0 +1,82 @@
+//! Fields following a request through the tasks it spawns.
+
+use crate::FieldValue;
+use std::{borrow::Cow, future::Future, sync::Arc};
+
+type Fields = Vec<(Cow<'static, str>, FieldValue)>;
+
+tokio::task_local! {
+    static CONTEXT: TaskContext;
+}
+
+/// Fields added to every event of a task and the tasks it spawns with [`spawn`], unlike
+/// thread locals surviving `.await` points and moves between threads.
+///
+/// ```no_run
+/// # use wgpu_subscriber::TaskContext;
+/// # async fn handle(request_id: u64) {
+/// TaskContext::current()
+///     .with_field("request_id", request_id)
+///     .scope(async {
+///         wgpu_subscriber::spawn(async { tracing::info!("has request_id") });
+///     })
+///     .await;
+/// # }
+/// ```
+///
+/// Events keep fields of their own with the same name.
+#[derive(Debug, Clone, Default)]
+pub struct TaskContext {
+    fields: Arc<Fields>,
+}
+
+impl TaskContext {
+    /// An empty context.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The context of the current task, empty outside of [`TaskContext::scope`].
+    pub fn current() -> Self {
+        CONTEXT.try_with(Clone::clone).unwrap_or_default()
+    }
+
+    /// Add or replace a field.
+    pub fn with_field(
+        mut self,
+        name: impl Into<Cow<'static, str>>,
+        value: impl Into<FieldValue>,
+    ) -> Self {
+        let name = name.into();
+        let value = value.into();
+        let fields = Arc::make_mut(&mut self.fields);
+        match fields.iter_mut().find(|(field, _)| *field == name) {
+            Some((_, current)) => *current = value,
+            None => fields.push((name, value)),
+        }
+        self
+    }
+
+    /// Run `future` with this context.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CONTEXT.scope(self, future).await
+    }
+
+    /// Calls `f` with the fields of the current task, if any.
+    pub(crate) fn with_current(f: impl FnOnce(&[(Cow<'static, str>, FieldValue)])) {
+        let _ = CONTEXT.try_with(|context| f(&context.fields));
+    }
+}
+
+/// Spawn `future` on the current tokio runtime, in the [`TaskContext`] of the caller.
+///
+/// # Panics
+///
+/// When called outside of a tokio runtime.
+pub fn spawn<F>(future: F) -> tokio::task::JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::spawn(TaskContext::current().scope(future))
+}
@@ -0,0 +1,186 @@
+//! Interactive terminal viewer over an in-memory ring buffer of lines.
+
+use parking_lot::Mutex;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::Paragraph,
+    DefaultTerminal, Frame,
+};
+use std::{collections::VecDeque, io, sync::Arc, time::Duration};
+use tracing::{level_filters::LevelFilter, Level};
+
+/// The last lines written, shared between a [`FmtLayer`](crate::FmtLayer) and its
+/// [`TuiViewer`].
+pub(crate) struct Ring {
+    lines: Mutex<VecDeque<(Level, String)>>,
+    capacity: usize,
+}
+
+impl Ring {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Ring {
+            lines: Mutex::new(VecDeque::with_capacity(capacity.min(4096))),
+            capacity,
+        }
+    }
+
+    pub(crate) fn push(&self, level: Level, line: &str) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lines = self.lines.lock();
+        if lines.len() >= self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back((level, line.to_owned()));
+    }
+}
+
+/// Terminal UI showing the lines of a [`FmtLayer::with_tui`](crate::FmtLayer::with_tui).
+///
+/// Keys:
+/// - `1` to `5`: show only ERROR up to TRACE and above.
+/// - `/`: type a search, only lines containing it are shown. `Enter` keeps it, `Esc`
+///   clears it.
+/// - Up, Down, PageUp, PageDown: scroll back. `End` follows new lines again.
+/// - `q`: leave the viewer. Lines keep being collected.
+pub struct TuiViewer {
+    ring: Arc<Ring>,
+    level: LevelFilter,
+    search: String,
+    typing: bool,
+    /// Rows scrolled back from the newest line.
+    scroll: usize,
+}
+
+impl TuiViewer {
+    pub(crate) fn new(ring: Arc<Ring>) -> Self {
+        TuiViewer {
+            ring,
+            level: LevelFilter::TRACE,
+            search: String::new(),
+            typing: false,
+            scroll: 0,
+        }
+    }
+
+    /// Take over the terminal until `q` is pressed.
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut terminal = ratatui::try_init()?;
+        let result = self.event_loop(&mut terminal);
+        ratatui::try_restore()?;
+        result
+    }
+
+    fn event_loop(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if !event::poll(Duration::from_millis(100))? {
+                continue;
+            }
+            let key = match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key,
+                _ => continue,
+            };
+
+            if self.typing {
+                match key.code {
+                    KeyCode::Enter => self.typing = false,
+                    KeyCode::Esc => {
+                        self.typing = false;
+                        self.search.clear();
+                    }
+                    KeyCode::Backspace => {
+                        self.search.pop();
+                    }
+                    KeyCode::Char(c) => self.search.push(c),
+                    _ => {}
+                }
+                self.scroll = 0;
+                continue;
+            }
+
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Char('/') => self.typing = true,
+                KeyCode::Esc => self.search.clear(),
+                KeyCode::Char(c @ '1'..='5') => {
+                    self.level = match c {
+                        '1' => LevelFilter::ERROR,
+                        '2' => LevelFilter::WARN,
+                        '3' => LevelFilter::INFO,
+                        '4' => LevelFilter::DEBUG,
+                        _ => LevelFilter::TRACE,
+                    };
+                    self.scroll = 0;
+                }
+                KeyCode::Up => self.scroll += 1,
+                KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+                KeyCode::PageUp => self.scroll += 20,
+                KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(20),
+                KeyCode::End => self.scroll = 0,
+                _ => {}
+            }
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame<'_>) {
+        let [log_area, status_area] =
+            Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+
+        let lines = self.ring.lines.lock();
+        let total = lines.len();
+        let matching: Vec<_> = lines
+            .iter()
+            .filter(|(level, line)| *level <= self.level && line.contains(&*self.search))
+            .collect();
+        let shown = matching.len();
+        // Error reports span several rows.
+        let matching: Vec<_> = matching
+            .into_iter()
+            .flat_map(|(level, line)| line.lines().map(move |row| (*level, row)))
+            .collect();
+
+        let height = log_area.height as usize;
+        self.scroll = self.scroll.min(matching.len().saturating_sub(height));
+        let end = matching.len() - self.scroll;
+        let start = end.saturating_sub(height);
+        let rows: Vec<_> = matching[start..end]
+            .iter()
+            .map(|(level, row)| Line::styled(row.to_string(), level_style(level)))
+            .collect();
+        drop(lines);
+
+        frame.render_widget(Paragraph::new(rows), log_area);
+
+        let search = match (self.typing, self.search.is_empty()) {
+            (true, _) => format!(" | search: {}_", self.search),
+            (false, false) => format!(" | search: {}", self.search),
+            (false, true) => String::new(),
+        };
+        let follow = match self.scroll {
+            0 => "",
+            _ => " | scrolled, End to follow",
+        };
+        let status = format!(
+            "level: {}{} | {} of {} lines{} | q quit, 1-5 level, / search",
+            self.level, search, shown, total, follow
+        );
+        let status_style = Style::default().add_modifier(Modifier::REVERSED);
+        frame.render_widget(Paragraph::new(status).style(status_style), status_area);
+    }
+}
+
+fn level_style(level: &Level) -> Style {
+    let color = match *level {
+        Level::ERROR => Color::Red,
+        Level::WARN => Color::Yellow,
+        Level::INFO => Color::Reset,
+        Level::DEBUG => Color::Blue,
+        Level::TRACE => Color::DarkGray,
+    };
+    Style::default().fg(color)
+}
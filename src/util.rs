@@ -0,0 +1,47 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+/// A cheap, non-cryptographic random number. Every call gets a freshly keyed SipHash
+/// of a global counter, which is plenty for sampling decisions and ids.
+pub(crate) fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+/// Whether `target` is `module` or inside of it, so `payment` contains `payment::card` but
+/// not `payments`.
+pub(crate) fn in_module(target: &str, module: &str) -> bool {
+    target
+        .strip_prefix(module)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// Id of this run of the process, a random UUID generated the first time it is needed, at
+/// the latest when a [`FmtLayer`](crate::FmtLayer) is created. See
+/// [`FmtLayer::with_run_id`](crate::FmtLayer::with_run_id).
+pub fn run_id() -> &'static str {
+    static RUN_ID: OnceLock<String> = OnceLock::new();
+
+    RUN_ID.get_or_init(|| {
+        // Version 4, variant 1.
+        let high = random_u64() & !0xf000 | 0x4000;
+        let low = random_u64() & !(0b11 << 62) | 0b10 << 62;
+        format!(
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            high >> 32,
+            (high >> 16) & 0xffff,
+            high & 0xffff,
+            low >> 48,
+            low & 0xffff_ffff_ffff,
+        )
+    })
+}
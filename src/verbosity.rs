@@ -0,0 +1,301 @@
+use std::{
+    any::TypeId,
+    cell::Cell,
+    fmt,
+    marker::PhantomData,
+    sync::atomic::{AtomicU8, Ordering},
+};
+use tracing::{
+    callsite,
+    field::{Field, Visit},
+    level_filters::LevelFilter,
+    span,
+    subscriber::Interest,
+    Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+thread_local! {
+    /// Raised verbosity of the current thread, see [`raise_level`].
+    static THREAD_LEVEL: Cell<Option<LevelFilter>> = const { Cell::new(None) };
+}
+
+/// Highest level raised so far, 0 if none was. Callsites above it that the filter disables
+/// can never be enabled, so that decision is cached.
+static HIGHEST_RAISED: AtomicU8 = AtomicU8::new(0);
+
+fn level_index(level: LevelFilter) -> u8 {
+    match level {
+        LevelFilter::OFF => 0,
+        LevelFilter::ERROR => 1,
+        LevelFilter::WARN => 2,
+        LevelFilter::INFO => 3,
+        LevelFilter::DEBUG => 4,
+        LevelFilter::TRACE => 5,
+    }
+}
+
+fn highest_raised() -> LevelFilter {
+    match HIGHEST_RAISED.load(Ordering::Acquire) {
+        0 => LevelFilter::OFF,
+        1 => LevelFilter::ERROR,
+        2 => LevelFilter::WARN,
+        3 => LevelFilter::INFO,
+        4 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+/// Records that `level` is raised somewhere, invalidating the callsite cache the first time
+/// a level this high is.
+fn note_raised(level: LevelFilter) {
+    let index = level_index(level);
+    if HIGHEST_RAISED.fetch_max(index, Ordering::AcqRel) < index {
+        invalidate_filter_cache();
+    }
+}
+
+/// Make every callsite ask the subscriber again whether it is enabled.
+///
+/// The decision is cached per callsite, so disabled events cost next to nothing. Call this
+/// after changing a filter at runtime in a way the subscriber isn't told about. Filters of
+/// this crate, like [`raise_level`], do it themselves when needed.
+pub fn invalidate_filter_cache() {
+    callsite::rebuild_interest_cache();
+}
+
+/// Raised verbosity of a span and all spans created below it.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct SpanLevel(pub(crate) LevelFilter);
+
+/// Picks the level out of a span's level field.
+struct LevelVisitor {
+    field: &'static str,
+    level: Option<LevelFilter>,
+}
+
+impl Visit for LevelVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == self.field {
+            self.level = value.parse().ok();
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == self.field {
+            self.level = format!("{:?}", value).trim_matches('"').parse().ok();
+        }
+    }
+}
+
+/// Run `f` with everything up to `level` enabled on the current thread, regardless of
+/// other filters, see [`ScopedVerbosity`].
+///
+/// ```
+/// # fn handle_request() {}
+/// wgpu_subscriber::with_level(tracing::Level::TRACE, || handle_request());
+/// ```
+pub fn with_level<R>(level: Level, f: impl FnOnce() -> R) -> R {
+    let _guard = raise_level(level);
+    f()
+}
+
+/// Enable everything up to `level` on the current thread until the guard is dropped.
+///
+/// Spans created meanwhile keep the raised level for their whole lifetime, so it follows
+/// a request into tasks that are entered on other threads.
+pub fn raise_level(level: Level) -> VerbosityGuard {
+    note_raised(LevelFilter::from(level));
+    let previous = THREAD_LEVEL.with(|current| current.replace(Some(LevelFilter::from(level))));
+    VerbosityGuard {
+        previous,
+        _not_send: PhantomData,
+    }
+}
+
+/// Restores the previous verbosity of the thread when dropped, see [`raise_level`].
+#[must_use = "the level is only raised until the guard is dropped"]
+#[derive(Debug)]
+pub struct VerbosityGuard {
+    previous: Option<LevelFilter>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for VerbosityGuard {
+    fn drop(&mut self) {
+        THREAD_LEVEL.with(|current| current.set(self.previous));
+    }
+}
+
+/// Wraps a filter like [`EnvFilter`](tracing_subscriber::EnvFilter), additionally enabling
+/// events and spans up to the level raised by [`with_level`] and [`raise_level`].
+///
+/// Without this layer these functions have no effect. Callsites disabled by `filter` are
+/// skipped outright until a level at least as high as theirs is raised somewhere, from then
+/// on they are checked each time they are hit. The first time a level is raised, the cached
+/// decisions of all callsites are invalidated, see [`invalidate_filter_cache`].
+///
+/// ```no_run
+/// # use tracing_subscriber::layer::SubscriberExt as _;
+/// # use wgpu_subscriber::{FmtLayer, ScopedVerbosity};
+/// let subscriber = tracing_subscriber::Registry::default()
+///     .with(FmtLayer::new())
+///     .with(ScopedVerbosity::new(tracing_subscriber::EnvFilter::from_default_env()));
+/// ```
+///
+/// A span can also raise the level of everything inside it, with a `log_level` field
+/// holding a level like `"debug"` or `"trace"`. This lights up a single request, for
+/// example when it carries a debug header. The span itself has to be enabled, and spans
+/// created below it before the field is recorded keep their level.
+///
+/// ```
+/// # let debug_header: Option<&str> = None;
+/// let span = tracing::info_span!("request", log_level = tracing::field::Empty);
+/// if let Some(level) = debug_header {
+///     span.record("log_level", &level);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ScopedVerbosity<F> {
+    filter: F,
+    level_field: &'static str,
+}
+
+impl<F> ScopedVerbosity<F> {
+    pub fn new(filter: F) -> Self {
+        ScopedVerbosity {
+            filter,
+            level_field: "log_level",
+        }
+    }
+
+    /// Name of the span field raising the level, `log_level` by default.
+    pub fn with_level_field(mut self, field: &'static str) -> Self {
+        self.level_field = field;
+        self
+    }
+
+    /// The raised level in effect for `ctx`, if any.
+    fn raised<S>(ctx: &Context<'_, S>) -> Option<LevelFilter>
+    where
+        S: Subscriber + for<'span> LookupSpan<'span>,
+    {
+        let span = ctx
+            .lookup_current()
+            .and_then(|span| span.extensions().get::<SpanLevel>().copied())
+            .map(|SpanLevel(level)| level);
+        let thread = THREAD_LEVEL.with(Cell::get);
+        span.max(thread)
+    }
+}
+
+impl<S, F> Layer<S> for ScopedVerbosity<F>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    F: Layer<S>,
+{
+    fn on_layer(&mut self, subscriber: &mut S) {
+        self.filter.on_layer(subscriber);
+    }
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        let interest = self.filter.register_callsite(metadata);
+        if interest.is_never() && *metadata.level() <= highest_raised() {
+            // Might be enabled by a raised level.
+            Interest::sometimes()
+        } else {
+            interest
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, ctx: Context<'_, S>) -> bool {
+        if Self::raised(&ctx).is_some_and(|level| *metadata.level() <= level) {
+            return true;
+        }
+        self.filter.enabled(metadata, ctx)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        self.filter
+            .max_level_hint()
+            .map(|level| level.max(highest_raised()))
+    }
+
+    fn new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).unwrap();
+        let parent = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<SpanLevel>().copied())
+            .map(|SpanLevel(level)| level);
+
+        let mut visitor = LevelVisitor {
+            field: self.level_field,
+            level: None,
+        };
+        attrs.record(&mut visitor);
+
+        if let Some(level) = visitor.level {
+            note_raised(level);
+        }
+        let level = parent.max(THREAD_LEVEL.with(Cell::get)).max(visitor.level);
+        if let Some(level) = level {
+            span.extensions_mut().replace(SpanLevel(level));
+        }
+        drop(span);
+
+        self.filter.new_span(attrs, id, ctx);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = LevelVisitor {
+            field: self.level_field,
+            level: None,
+        };
+        values.record(&mut visitor);
+
+        if let (Some(level), Some(span)) = (visitor.level, ctx.span(id)) {
+            note_raised(level);
+            let mut extensions = span.extensions_mut();
+            let level = match extensions.get_mut::<SpanLevel>() {
+                Some(SpanLevel(current)) => level.max(*current),
+                None => level,
+            };
+            extensions.replace(SpanLevel(level));
+        }
+
+        self.filter.on_record(id, values, ctx);
+    }
+
+    fn on_follows_from(&self, span: &span::Id, follows: &span::Id, ctx: Context<'_, S>) {
+        self.filter.on_follows_from(span, follows, ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        self.filter.on_event(event, ctx);
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.filter.on_enter(id, ctx);
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: Context<'_, S>) {
+        self.filter.on_exit(id, ctx);
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        self.filter.on_close(id, ctx);
+    }
+
+    fn on_id_change(&self, old: &span::Id, new: &span::Id, ctx: Context<'_, S>) {
+        self.filter.on_id_change(old, new, ctx);
+    }
+
+    #[doc(hidden)]
+    unsafe fn downcast_raw(&self, id: TypeId) -> Option<*const ()> {
+        if id == TypeId::of::<Self>() {
+            Some(self as *const Self as *const ())
+        } else {
+            self.filter.downcast_raw(id)
+        }
+    }
+}
@@ -0,0 +1,750 @@
+use crate::{
+    format::{Format, FormatOptions},
+    quota::Quota,
+    stats::{record_drop, DropReason},
+    BuildError, FieldValue, LogRecord, Query,
+};
+use parking_lot::Mutex;
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufWriter, Write as _},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::Duration,
+};
+use tracing::{level_filters::LevelFilter, Level};
+
+/// When buffered output is flushed, see [`FmtLayer::with_flush`](crate::FmtLayer::with_flush).
+///
+/// Only standard streams and files are buffered, sockets always get one write per line.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Flush {
+    /// Flush after every line, so output shows up immediately even when redirected.
+    /// This is the default.
+    Line,
+    /// Only flush when the buffer is full, or the layer is dropped. Fastest, but the last
+    /// lines may be lost if the process exits without dropping the layer.
+    Buffered,
+    /// Flush at least this often from a background thread, see
+    /// [`after_fork`](crate::after_fork) for forked processes.
+    Interval(Duration),
+}
+
+/// Where a [`FmtLayer`](crate::FmtLayer) sends its formatted lines.
+pub(crate) enum Output {
+    /// ERROR/WARN to stderr, everything else to stdout.
+    Std {
+        out: Mutex<BufWriter<io::Stdout>>,
+        err: Mutex<BufWriter<io::Stderr>>,
+    },
+    File(Mutex<BufWriter<File>>),
+    SharedFile(Mutex<crate::shared_file::SharedFile>),
+    CappedFile(Mutex<crate::capped_file::CappedFile>),
+    /// Through `print!` and `eprint!`, which the test harness captures.
+    Test,
+    Lazy(crate::lazy::LazyOutput),
+    Snapshot(crate::Snapshot),
+    #[cfg(feature = "encryption")]
+    Encrypted(Mutex<crate::encryption::EncryptedFile>),
+    #[cfg(any(unix, windows))]
+    Socket(Mutex<crate::socket::SocketWriter>),
+    #[cfg(feature = "tui")]
+    Ring(std::sync::Arc<crate::tui::Ring>),
+}
+
+impl Output {
+    pub(crate) fn std() -> Self {
+        Output::Std {
+            out: Mutex::new(BufWriter::new(io::stdout())),
+            err: Mutex::new(BufWriter::new(io::stderr())),
+        }
+    }
+
+    pub(crate) fn file(path: &Path) -> io::Result<Self> {
+        File::create(path).map(|file| Output::File(Mutex::new(BufWriter::new(file))))
+    }
+
+    /// Which stream of the output a line at `level` goes to, 1 for stderr.
+    fn stream(&self, level: &Level) -> usize {
+        match self {
+            Output::Std { .. } | Output::Test => match *level {
+                Level::ERROR | Level::WARN => 1,
+                _ => 0,
+            },
+            Output::Lazy(lazy) => lazy.get().map_or(0, |output| output.stream(level)),
+            _ => 0,
+        }
+    }
+
+    /// Whether a file appended to already holds lines, from a previous run or another
+    /// process.
+    fn has_lines(&self) -> bool {
+        match self {
+            Output::SharedFile(file) => file.lock().len() != 0,
+            Output::CappedFile(file) => file.lock().len() != 0,
+            Output::Lazy(lazy) => lazy.get().is_ok_and(Output::has_lines),
+            _ => false,
+        }
+    }
+
+    /// Writes a single formatted line, without its trailing newline.
+    pub(crate) fn write_line(&self, level: &Level, line: &str, flush: Flush) -> io::Result<()> {
+        fn write(writer: &mut impl io::Write, line: &str, flush: Flush) -> io::Result<()> {
+            writer.write_all(line.as_bytes())?;
+            writer.write_all(b"\n")?;
+            if flush == Flush::Line {
+                writer.flush()?;
+            }
+            Ok(())
+        }
+
+        match self {
+            Output::Std { out, err } => match *level {
+                Level::ERROR | Level::WARN => write(&mut *err.lock(), line, flush),
+                _ => write(&mut *out.lock(), line, flush),
+            },
+            Output::File(file) => write(&mut *file.lock(), line, flush),
+            Output::SharedFile(file) => file.lock().write_line(line, flush == Flush::Line),
+            Output::CappedFile(file) => file.lock().write_line(line, flush == Flush::Line),
+            Output::Lazy(lazy) => lazy.get()?.write_line(level, line, flush),
+            Output::Snapshot(snapshot) => {
+                snapshot.push(line);
+                Ok(())
+            }
+            Output::Test => {
+                match *level {
+                    Level::ERROR | Level::WARN => eprintln!("{}", line),
+                    _ => println!("{}", line),
+                }
+                Ok(())
+            }
+            #[cfg(feature = "encryption")]
+            Output::Encrypted(file) => {
+                let mut file = file.lock();
+                file.write_record(line.as_bytes())?;
+                if flush == Flush::Line {
+                    file.flush()?;
+                }
+                Ok(())
+            }
+            #[cfg(any(unix, windows))]
+            Output::Socket(socket) => socket.lock().write_line(line),
+            #[cfg(feature = "tui")]
+            Output::Ring(ring) => {
+                ring.push(*level, line);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes an already framed binary record.
+    pub(crate) fn write_frame(&self, level: &Level, frame: &[u8], flush: Flush) -> io::Result<()> {
+        fn write(writer: &mut impl io::Write, frame: &[u8], flush: Flush) -> io::Result<()> {
+            writer.write_all(frame)?;
+            if flush == Flush::Line {
+                writer.flush()?;
+            }
+            Ok(())
+        }
+
+        match self {
+            Output::Std { out, err } => match *level {
+                Level::ERROR | Level::WARN => write(&mut *err.lock(), frame, flush),
+                _ => write(&mut *out.lock(), frame, flush),
+            },
+            Output::File(file) => write(&mut *file.lock(), frame, flush),
+            Output::SharedFile(file) => file.lock().write_frame(frame, flush == Flush::Line),
+            // Frames can't be told apart to drop the oldest ones.
+            Output::CappedFile(_) => Err(io::ErrorKind::Unsupported.into()),
+            Output::Lazy(lazy) => lazy.get()?.write_frame(level, frame, flush),
+            Output::Snapshot(_) | Output::Test => Err(io::ErrorKind::Unsupported.into()),
+            // Decrypting only gives back text.
+            #[cfg(feature = "encryption")]
+            Output::Encrypted(_) => Err(io::ErrorKind::Unsupported.into()),
+            #[cfg(any(unix, windows))]
+            Output::Socket(socket) => socket.lock().write_bytes(frame),
+            #[cfg(feature = "tui")]
+            Output::Ring(_) => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    /// Name of the kind of output when it can't take binary frames.
+    fn without_frames(&self) -> Option<&'static str> {
+        match self {
+            Output::Test => Some("test writer"),
+            Output::Snapshot(_) => Some("snapshot"),
+            Output::CappedFile(_) => Some("capped file"),
+            #[cfg(feature = "encryption")]
+            Output::Encrypted(_) => Some("encrypted file"),
+            #[cfg(feature = "tui")]
+            Output::Ring(_) => Some("TUI"),
+            _ => None,
+        }
+    }
+
+    /// Moves the events of the sink since the last call to `out`.
+    pub(crate) fn take_events(&self, out: &mut Vec<SinkEvent>) {
+        match self {
+            Output::CappedFile(file) => file.lock().take_events(out),
+            Output::Lazy(lazy) => lazy.take_events(out),
+            #[cfg(any(unix, windows))]
+            Output::Socket(socket) => socket.lock().take_events(out),
+            _ => {}
+        }
+    }
+
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        match self {
+            Output::Std { out, err } => {
+                out.lock().flush()?;
+                err.lock().flush()
+            }
+            Output::File(file) => file.lock().flush(),
+            Output::SharedFile(file) => file.lock().flush(),
+            Output::CappedFile(file) => file.lock().flush(),
+            Output::Lazy(lazy) => lazy.opened().map_or(Ok(()), Output::flush),
+            Output::Snapshot(_) | Output::Test => Ok(()),
+            #[cfg(feature = "encryption")]
+            Output::Encrypted(file) => file.lock().flush(),
+            #[cfg(any(unix, windows))]
+            Output::Socket(_) => Ok(()),
+            #[cfg(feature = "tui")]
+            Output::Ring(_) => Ok(()),
+        }
+    }
+}
+
+/// Something that happened to a sink, output as a record of its own, see
+/// [`FmtLayer::with_sink_events`](crate::FmtLayer::with_sink_events).
+#[derive(Debug)]
+pub(crate) struct SinkEvent {
+    message: &'static str,
+    fields: Vec<(&'static str, FieldValue)>,
+}
+
+impl SinkEvent {
+    pub(crate) fn new(message: &'static str, path: &Path) -> Self {
+        SinkEvent {
+            message,
+            fields: vec![("sink", FieldValue::Str(path.display().to_string()))],
+        }
+    }
+
+    /// An event of a sink without a path.
+    pub(crate) fn unnamed(message: &'static str) -> Self {
+        SinkEvent {
+            message,
+            fields: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_field(mut self, name: &'static str, value: FieldValue) -> Self {
+        self.fields.push((name, value));
+        self
+    }
+
+    pub(crate) fn into_record(self, time: Duration, level: Level) -> LogRecord {
+        let mut record = LogRecord::internal(time, level, self.message.to_owned());
+        let fields = self
+            .fields
+            .into_iter()
+            .map(|(name, value)| (Cow::Borrowed(name), value));
+        record.fields.extend(fields);
+        record
+    }
+}
+
+/// One destination of a [`FmtLayer`](crate::FmtLayer), with its own format and level,
+/// see [`FmtLayer::with_sink`](crate::FmtLayer::with_sink).
+///
+/// ```no_run
+/// # use wgpu_subscriber::{FmtLayer, Format, Sink};
+/// let layer = FmtLayer::new()
+///     .with_sink(Sink::file("app.log")?.with_format(Format::Json))
+///     .with_sink(Sink::socket("/run/collector.sock").with_format(Format::Logfmt));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+///
+/// Levels can be routed to different files, all formatted the same way. Here everything goes
+/// to `app.log`, warnings and errors also to `errors.log`, and DEBUG and TRACE events also to
+/// `debug.log`.
+///
+/// ```no_run
+/// # use tracing::Level;
+/// # use wgpu_subscriber::{FmtLayer, Sink};
+/// let layer = FmtLayer::with_file("app.log")?
+///     .with_sink(Sink::file("errors.log")?.with_max_level(Level::WARN))
+///     .with_sink(Sink::file("debug.log")?.with_min_level(Level::DEBUG));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct Sink {
+    pub(crate) output: Arc<Output>,
+    format: Option<Format>,
+    encoding: Encoding,
+    max_level: Option<LevelFilter>,
+    min_level: Option<Level>,
+    query: Option<Arc<Query>>,
+    quota: Option<Arc<Quota>>,
+    /// Overrides the flush of the layer.
+    pub(crate) flush: Option<Flush>,
+    /// Bytes written so far, by all clones.
+    bytes: Arc<AtomicU64>,
+    /// Whether the [`Format::Csv`] header row was written, per stream of the output.
+    header_written: Arc<[AtomicBool; 2]>,
+    /// Set once whatever flushes the sink is gone, from then on every line is flushed.
+    shut_down: Arc<AtomicBool>,
+}
+
+/// Binary encodings a [`Sink`] can write instead of formatted lines.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Encoding {
+    Text,
+    MessagePack,
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+impl Sink {
+    fn new(output: Output) -> Self {
+        Sink {
+            output: Arc::new(output),
+            format: None,
+            encoding: Encoding::Text,
+            max_level: None,
+            min_level: None,
+            query: None,
+            quota: None,
+            flush: None,
+            bytes: Arc::new(AtomicU64::new(0)),
+            header_written: Arc::default(),
+            shut_down: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// ERROR/WARN to stderr, everything else to stdout.
+    pub fn stdout() -> Self {
+        Self::new(Output::std())
+    }
+
+    /// Like [`Sink::stdout`], but captured by `cargo test` like the output of `println!`,
+    /// see [`FmtLayer::with_test_writer`](crate::FmtLayer::with_test_writer).
+    pub fn test() -> Self {
+        Self::new(Output::Test)
+    }
+
+    /// The given file, cleared if it exists.
+    pub fn file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Output::file(path.as_ref()).map(Self::new)
+    }
+
+    /// The given file, appended to without clearing it, safely shared with other processes
+    /// doing the same, see [`FmtLayer::with_shared_file`](crate::FmtLayer::with_shared_file).
+    pub fn shared_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        crate::shared_file::SharedFile::open(path.as_ref())
+            .map(|file| Self::new(Output::SharedFile(Mutex::new(file))))
+    }
+
+    /// The given file, kept under `max_bytes` by dropping its oldest lines, see
+    /// [`FmtLayer::with_capped_file`](crate::FmtLayer::with_capped_file).
+    pub fn capped_file(path: impl AsRef<Path>, max_bytes: u64) -> io::Result<Self> {
+        crate::capped_file::CappedFile::open(path.as_ref(), max_bytes)
+            .map(|file| Self::new(Output::CappedFile(Mutex::new(file))))
+    }
+
+    /// The given file, with every line encrypted for `recipient`, see
+    /// [`FmtLayer::with_encrypted_file`](crate::FmtLayer::with_encrypted_file).
+    #[cfg(feature = "encryption")]
+    pub fn encrypted_file(path: impl AsRef<Path>, recipient: crate::PublicKey) -> io::Result<Self> {
+        crate::encryption::EncryptedFile::create(path.as_ref(), recipient)
+            .map(|file| Self::new(Output::Encrypted(Mutex::new(file))))
+    }
+
+    /// A Unix domain socket or Windows named pipe, see
+    /// [`FmtLayer::with_socket`](crate::FmtLayer::with_socket).
+    #[cfg(any(unix, windows))]
+    pub fn socket(path: impl Into<std::path::PathBuf>) -> Self {
+        let socket = crate::socket::SocketWriter::new(path.into());
+        Self::new(Output::Socket(Mutex::new(socket)))
+    }
+
+    /// Like [`Sink::socket`], but while disconnected lines are appended to the file at
+    /// `spool` instead of being dropped, until it holds `max_bytes`. They are sent
+    /// before any new line once connected again.
+    ///
+    /// The spool file is kept, lines left in it by a previous run are sent too. Once sent,
+    /// a `sink replayed spooled lines` record gives their size in `bytes`.
+    ///
+    /// If the connection is lost while sending them, they are all sent again on the next
+    /// one, and the line or frame it was lost in arrives cut. Readers of binary frames, like
+    /// those of [`Sink::with_message_pack`], can't skip past it.
+    #[cfg(any(unix, windows))]
+    pub fn spooled_socket(
+        path: impl Into<std::path::PathBuf>,
+        spool: impl AsRef<Path>,
+        max_bytes: u64,
+    ) -> io::Result<Self> {
+        let spool = crate::spool::Spool::open(spool.as_ref(), max_bytes)?;
+        let socket = crate::socket::SocketWriter::with_spool(path.into(), spool);
+        Ok(Self::new(Output::Socket(Mutex::new(socket))))
+    }
+
+    /// The output of the sink returned by `open`, called on the first record written to
+    /// this one, so that a file is only created or a connection made if it is needed. Its
+    /// format and levels are those of this sink, not of the returned one.
+    ///
+    /// ```no_run
+    /// # use tracing::Level;
+    /// # use wgpu_subscriber::{FmtLayer, Sink};
+    /// let layer = FmtLayer::new()
+    ///     .with_sink(Sink::lazy(|| Sink::file("errors.log")).with_max_level(Level::ERROR));
+    /// ```
+    ///
+    /// If `open` fails, the sink drops every record, and a `sink failed to open` record is
+    /// output, see [`FmtLayer::with_sink_events`](crate::FmtLayer::with_sink_events).
+    pub fn lazy(open: impl FnOnce() -> io::Result<Sink> + Send + 'static) -> Self {
+        Self::new(Output::Lazy(crate::lazy::LazyOutput::new(open)))
+    }
+
+    /// Lines kept in memory for the returned [`Snapshot`](crate::Snapshot), see
+    /// [`FmtLayer::with_snapshot`](crate::FmtLayer::with_snapshot).
+    pub fn snapshot() -> (Self, crate::Snapshot) {
+        let snapshot = crate::Snapshot::default();
+        (Self::new(Output::Snapshot(snapshot.clone())), snapshot)
+    }
+
+    /// The last `capacity` lines in memory, for the returned viewer to show, see
+    /// [`FmtLayer::with_tui`](crate::FmtLayer::with_tui).
+    #[cfg(feature = "tui")]
+    pub fn tui(capacity: usize) -> (Self, crate::TuiViewer) {
+        let ring = Arc::new(crate::tui::Ring::new(capacity));
+        let viewer = crate::TuiViewer::new(ring.clone());
+        (Self::new(Output::Ring(ring)), viewer)
+    }
+
+    /// Format records with `format` instead of the one of the layer.
+    pub fn with_format(mut self, format: Format) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Write records as MessagePack frames instead of lines, read them back with
+    /// [`read_message_pack`](crate::read_message_pack). Overrides any format.
+    ///
+    /// Records longer than 16 MiB once encoded can't be read back, they are dropped and
+    /// counted as write errors in [`dropped_events`](crate::dropped_events).
+    ///
+    /// Not supported by [`Sink::tui`], [`Sink::test`], [`Sink::snapshot`],
+    /// [`Sink::capped_file`] and [`Sink::encrypted_file`].
+    pub fn with_message_pack(mut self) -> Self {
+        self.encoding = Encoding::MessagePack;
+        self
+    }
+
+    /// Write records as length delimited [`proto::LogRecord`](crate::proto::LogRecord)
+    /// messages instead of lines, following `proto/log_record.proto`. Overrides any
+    /// format.
+    ///
+    /// Every message is prefixed with its length as varint, like `writeDelimitedTo` in
+    /// Java or [`prost::Message::decode_length_delimited`] expect.
+    ///
+    /// Not supported by [`Sink::tui`], [`Sink::test`], [`Sink::snapshot`],
+    /// [`Sink::capped_file`] and [`Sink::encrypted_file`].
+    #[cfg(feature = "protobuf")]
+    pub fn with_protobuf(mut self) -> Self {
+        self.encoding = Encoding::Protobuf;
+        self
+    }
+
+    /// When to flush this sink, instead of the [flush](crate::FmtLayer::with_flush) of the
+    /// layer. For example every line to the terminal, but only every second to a file.
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use wgpu_subscriber::{BuildError, Flush, FmtLayer, Sink};
+    /// let layer = FmtLayer::new()
+    ///     .with_sink(Sink::file("app.log")?.with_flush(Flush::Interval(Duration::from_secs(1))))
+    ///     .build()?;
+    /// # Ok::<(), BuildError>(())
+    /// ```
+    pub fn with_flush(mut self, flush: Flush) -> Self {
+        self.flush = Some(flush);
+        self
+    }
+
+    /// Only write events at `level` or above to this sink.
+    ///
+    /// This only filters further what the layer outputs, it can't enable more.
+    pub fn with_max_level(mut self, level: impl Into<LevelFilter>) -> Self {
+        self.max_level = Some(level.into());
+        self
+    }
+
+    /// Write at most `max_bytes` per `period`, like 100 MB a day to a sink whose ingestion is
+    /// billed. Once over it, records are dropped until the period ends, counted in
+    /// [`dropped_events`](crate::dropped_events). The sink gets a `sink quota exceeded,
+    /// suppressing records` record when that starts, and a `sink quota renewed` record
+    /// with the number of records and bytes suppressed before the next record after it
+    /// ends.
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use wgpu_subscriber::Sink;
+    /// let sink = Sink::socket("/run/collector.sock")
+    ///     .with_quota(100_000_000, Duration::from_secs(24 * 60 * 60));
+    /// ```
+    ///
+    /// Periods start with the first record, and again with the first record after one ends.
+    pub fn with_quota(mut self, max_bytes: u64, period: Duration) -> Self {
+        self.quota = Some(Arc::new(Quota::new(max_bytes, period)));
+        self
+    }
+
+    /// Bytes written to this sink and its clones so far, lines with their newline.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Whether a record of `bytes` fits the quota, writing the notices of the quota.
+    fn admit(
+        &self,
+        record: &LogRecord,
+        bytes: usize,
+        format: &Format,
+        options: &FormatOptions,
+        flush: Flush,
+    ) -> bool {
+        let quota = match self.quota {
+            Some(ref quota) => quota,
+            None => {
+                self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+                return true;
+            }
+        };
+        let (admitted, notice) = quota.admit(bytes as u64);
+        if let Some(notice) = notice {
+            let notice = notice.into_record(record.time);
+            self.write_alone(&notice, format, options, flush);
+        }
+        if admitted {
+            self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        } else {
+            record_drop(DropReason::Quota, 1);
+        }
+        admitted
+    }
+
+    /// Writes `record` to this sink only, in its format or encoding.
+    fn write_alone(
+        &self,
+        record: &LogRecord,
+        format: &Format,
+        options: &FormatOptions,
+        flush: Flush,
+    ) {
+        let result = match self.encoding {
+            Encoding::Text => {
+                let mut line = String::new();
+                format.write(record, options, &mut line);
+                self.bytes
+                    .fetch_add(line.len() as u64 + 1, Ordering::Relaxed);
+                self.output.write_line(&record.level, &line, flush)
+            }
+            encoding => {
+                let mut frame = Vec::new();
+                if !encode(encoding, record, &mut frame) {
+                    record_drop(DropReason::WriteError, 1);
+                    return;
+                }
+                self.bytes.fetch_add(frame.len() as u64, Ordering::Relaxed);
+                self.output.write_frame(&record.level, &frame, flush)
+            }
+        };
+        if result.is_err() {
+            record_drop(DropReason::WriteError, 1);
+        }
+    }
+
+    /// Fails if the sink can't write anything, see [`FmtLayer::build`](crate::FmtLayer::build).
+    pub(crate) fn check(&self, layer_format: &Format) -> Result<(), BuildError> {
+        if self.flush == Some(Flush::Interval(Duration::ZERO)) {
+            return Err(BuildError::FlushInterval);
+        }
+        if self
+            .quota
+            .as_ref()
+            .is_some_and(|quota| quota.period().is_zero())
+        {
+            return Err(BuildError::QuotaPeriod);
+        }
+        if self.encoding != Encoding::Text {
+            if let Some(output) = self.output.without_frames() {
+                return Err(BuildError::BinaryEncoding(output));
+            }
+            return Ok(());
+        }
+        match self.format.as_ref().unwrap_or(layer_format) {
+            Format::Csv(csv) => csv.check(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Flushes the sink for the last time, also flushing every line written after this.
+    pub(crate) fn shut_down(&self) {
+        self.shut_down.store(true, Ordering::Relaxed);
+        let _ = self.output.flush();
+    }
+
+    /// Only write events at `level` or below to this sink. Together with
+    /// [`Sink::with_max_level`], this routes a range of levels to it.
+    ///
+    /// Records about the sinks themselves, like `sink disconnected`, are still at the level
+    /// of [`FmtLayer::with_sink_events`](crate::FmtLayer::with_sink_events).
+    pub fn with_min_level(mut self, level: Level) -> Self {
+        self.min_level = Some(level);
+        self
+    }
+
+    /// Only write records matching `query` to this sink, on top of its levels.
+    ///
+    /// ```no_run
+    /// # use wgpu_subscriber::{BuildError, FmtLayer, Sink};
+    /// let layer = FmtLayer::new()
+    ///     .with_sink(Sink::file("payments.log")?.with_query(
+    ///         r#"level >= warn && target starts_with "payments" && fields.status >= 500"#
+    ///             .parse()?,
+    ///     ))
+    ///     .build()?;
+    /// # Ok::<(), BuildError>(())
+    /// ```
+    ///
+    /// Like levels, it doesn't apply to records about the sinks themselves.
+    pub fn with_query(mut self, query: Query) -> Self {
+        self.query = Some(Arc::new(query));
+        self
+    }
+}
+
+/// Writes `record` to every sink that wants it, formatting it once for consecutive sinks
+/// with the same format. `line` is reused as buffer, events of the sinks are added to
+/// `events`.
+pub(crate) fn write_record(
+    sinks: &[Sink],
+    format: &Format,
+    options: &FormatOptions,
+    flush: Flush,
+    record: &LogRecord,
+    line: &mut String,
+    events: &mut Vec<SinkEvent>,
+) {
+    let mut record = Cow::Borrowed(record);
+    if options.run_id && record.field("run_id").is_none() {
+        let run_id = FieldValue::Str(crate::run_id().to_owned());
+        record
+            .to_mut()
+            .fields
+            .push((Cow::Borrowed("run_id"), run_id));
+    }
+    let record = &*record;
+
+    let mut formatted = None;
+    let mut encoded: Option<(Encoding, Vec<u8>, bool)> = None;
+    for sink in sinks {
+        if sink
+            .max_level
+            .is_some_and(|max_level| record.level > max_level)
+            || sink
+                .min_level
+                .is_some_and(|min_level| record.level < min_level)
+            || sink
+                .query
+                .as_ref()
+                .is_some_and(|query| !query.matches(record))
+        {
+            continue;
+        }
+        let flush = match sink.shut_down.load(Ordering::Relaxed) {
+            true => Flush::Line,
+            false => sink.flush.unwrap_or(flush),
+        };
+        let result = if sink.encoding == Encoding::Text {
+            let format = sink.format.as_ref().unwrap_or(format);
+            if let Format::Csv(csv) = format {
+                let stream = sink.output.stream(&record.level);
+                if !sink.header_written[stream].swap(true, Ordering::Relaxed)
+                    && !sink.output.has_lines()
+                {
+                    let mut header = String::new();
+                    csv.write_header(&mut header);
+                    let _ = sink.output.write_line(&record.level, &header, flush);
+                }
+            }
+            if formatted != Some(format) {
+                line.clear();
+                format.write(record, options, line);
+                formatted = Some(format);
+            }
+            match sink.admit(record, line.len() + 1, format, options, flush) {
+                true => sink.output.write_line(&record.level, line, flush),
+                false => Ok(()),
+            }
+        } else {
+            if encoded
+                .as_ref()
+                .is_none_or(|(encoding, _, _)| *encoding != sink.encoding)
+            {
+                let mut frame = encoded
+                    .take()
+                    .map(|(_, frame, _)| frame)
+                    .unwrap_or_default();
+                frame.clear();
+                let fits = encode(sink.encoding, record, &mut frame);
+                encoded = Some((sink.encoding, frame, fits));
+            }
+            let (_, frame, fits) = encoded.as_ref().unwrap();
+            if !fits {
+                record_drop(DropReason::WriteError, 1);
+                continue;
+            }
+            match sink.admit(record, frame.len(), format, options, flush) {
+                true => sink.output.write_frame(&record.level, frame, flush),
+                false => Ok(()),
+            }
+        };
+        if result.is_err() {
+            record_drop(DropReason::WriteError, 1);
+        }
+        sink.output.take_events(events);
+    }
+}
+
+/// Appends `record` to `frame` in a binary `encoding`, with its length in front. Returns
+/// `false` if the frame is too long to be read back, it must then not be written.
+fn encode(encoding: Encoding, record: &LogRecord, frame: &mut Vec<u8>) -> bool {
+    match encoding {
+        Encoding::Text => unreachable!("text is formatted"),
+        Encoding::MessagePack => {
+            frame.extend_from_slice(&[0; 4]);
+            crate::msgpack::encode(record, frame);
+            let len = frame.len() - 4;
+            frame[..4].copy_from_slice(&(len as u32).to_le_bytes());
+            len <= crate::msgpack::MAX_FRAME
+        }
+        #[cfg(feature = "protobuf")]
+        Encoding::Protobuf => {
+            let message = crate::proto::LogRecord::from(record);
+            let _ = prost::Message::encode_length_delimited(&message, frame);
+            true
+        }
+    }
+}
+
+/// Flushes `output` every `interval` until it is dropped, replacing the thread that did so
+/// before. `None` only stops that thread.
+pub(crate) fn set_flusher(output: Weak<Output>, interval: Option<Duration>) {
+    crate::fork::spawn_worker(crate::fork::Worker::Flusher(output), interval);
+}